@@ -0,0 +1,180 @@
+use crate::session::Stats;
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use tracing::error;
+use anyhow::{anyhow, Context, Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    // One JSON object per line.
+    Json,
+    // Space separated fields, `-` for missing ones.
+    Text,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            "text" => Ok(Format::Text),
+            _ => Err(anyhow!("unknown access log format {:?}", s)),
+        }
+    }
+}
+
+// Lines waiting for the writer; past that records are dropped rather than
+// holding up the sessions that end.
+const QUEUE: usize = 100_000;
+
+// One line per finished session. Sessions only format their line, a thread
+// of its own does the writing, so a slow disk does not stall the runtime.
+// Dropping the log lets the thread write out what is queued and end.
+pub struct AccessLog {
+    format: Format,
+    lines: Mutex<Option<SyncSender<String>>>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl std::fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AccessLog").field("format", &self.format).finish()
+    }
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    time: String,
+    session: u64,
+    client: SocketAddr,
+    user: Option<&'a str>,
+    command: Option<&'a str>,
+    destination: Option<&'a str>,
+    resolved: Option<IpAddr>,
+    route: Option<&'a str>,
+    reply: Option<u8>,
+    bytes_up: u64,
+    bytes_down: u64,
+    duration_ms: u128,
+    close: &'a str,
+}
+
+impl AccessLog {
+    // Append to `path`, or write to stdout when there is none.
+    pub fn new(format: Format, path: Option<&Path>) -> Result<AccessLog> {
+        let out: Box<dyn Write + Send> = match path {
+            Some(path) => Box::new(OpenOptions::new().create(true).append(true).open(path)
+                .with_context(|| format!("can not open access log {}", path.display()))?),
+            None => Box::new(std::io::stdout()),
+        };
+        let (lines, rx) = sync_channel(QUEUE);
+        let writer = std::thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || write_lines(out, rx))
+            .context("can not start the access log writer")?;
+        Ok(AccessLog { format, lines: Mutex::new(Some(lines)), writer: Mutex::new(Some(writer)) })
+    }
+
+    // Stop taking records and return the writer thread, which ends once what
+    // is queued is written. Joining it blocks, so not on the runtime.
+    pub fn close(&self) -> Option<JoinHandle<()>> {
+        self.lines.lock().unwrap().take();
+        self.writer.lock().unwrap().take()
+    }
+
+    pub fn write(&self, stats: &Stats) {
+        let details = stats.details();
+        let record = Record {
+            time: humantime::format_rfc3339_millis(stats.start_time).to_string(),
+            session: stats.id,
+            client: stats.client,
+            user: details.user.as_deref(),
+            command: details.command,
+            destination: details.destination.as_deref(),
+            resolved: details.resolved,
+            route: details.route.as_deref(),
+            reply: details.reply,
+            bytes_up: stats.bytes_up.load(Ordering::Relaxed),
+            bytes_down: stats.bytes_down.load(Ordering::Relaxed),
+            duration_ms: stats.started.elapsed().as_millis(),
+            // Nothing recorded a reason: the session was dropped, e.g. on forced shutdown.
+            close: details.close.unwrap_or("aborted"),
+        };
+        let line = match self.format {
+            Format::Json => serde_json::to_string(&record).unwrap_or_default(),
+            Format::Text => {
+                let or_dash = |v: Option<String>| v.unwrap_or_else(|| "-".to_string());
+                format!(
+                    "{} {} {} {} {} {} {} {} {} {} {} {}ms {}",
+                    record.time,
+                    record.session,
+                    record.client,
+                    record.user.unwrap_or("-"),
+                    record.command.unwrap_or("-"),
+                    record.destination.unwrap_or("-"),
+                    or_dash(record.resolved.map(|ip| ip.to_string())),
+                    record.route.unwrap_or("-"),
+                    or_dash(record.reply.map(|rep| format!("{:#04x}", rep))),
+                    record.bytes_up,
+                    record.bytes_down,
+                    record.duration_ms,
+                    record.close,
+                )
+            },
+        };
+        if let Some(Err(TrySendError::Full(_))) = self.lines.lock().unwrap().as_ref().map(|lines| lines.try_send(line)) {
+            error!("access log writer is behind, record of session {} dropped", stats.id);
+        }
+    }
+}
+
+fn write_lines(out: Box<dyn Write + Send>, lines: Receiver<String>) {
+    let mut out = BufWriter::new(out);
+    while let Ok(line) = lines.recv() {
+        // Whatever else is queued goes out with the same flush.
+        let res = std::iter::once(line).chain(lines.try_iter())
+            .try_for_each(|line| writeln!(out, "{}", line))
+            .and_then(|_| out.flush());
+        if let Err(e) = res {
+            error!("can not write access log: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::session::Session;
+    use std::sync::Arc;
+
+    #[test]
+    fn queued_sessions_are_written_on_close() {
+        let path = std::env::temp_dir().join(format!("socks-access-{}.log", std::process::id()));
+        let config = Config {
+            access_log: Some(Arc::new(AccessLog::new(Format::Text, Some(&path)).unwrap())),
+            ..Config::default()
+        };
+        let addr: SocketAddr = "127.0.0.1:1080".parse().unwrap();
+        for _ in 0..3 {
+            let session = Session::new(addr, addr, "192.0.2.1:40000".parse().unwrap(), &config);
+            session.stats.details().command = Some("connect");
+            session.stats.close("completed");
+        }
+        let writer = config.access_log.as_ref().unwrap().close().unwrap();
+        writer.join().unwrap();
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(log.lines().count(), 3);
+        assert!(log.lines().all(|line| line.contains(" 192.0.2.1:40000 - connect ") && line.ends_with(" completed")));
+    }
+}
@@ -0,0 +1,328 @@
+use crate::config::Shared;
+use crate::http::read_head;
+use crate::logging;
+use crate::metrics::METRICS;
+use crate::session::REGISTRY;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tracing_subscriber::filter::LevelFilter;
+use tracing::{debug, info};
+use anyhow::{Error, Result};
+
+// A client that has not sent its whole request by then is disconnected.
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+// Environment variable with the admin API token, read by `serve` and `ctl`.
+pub const TOKEN_ENV: &str = "SOCKS_ADMIN_TOKEN";
+
+/// Where the admin API listens: a TCP address, or a Unix socket path.
+/// Parsed from `host:port`, or a path containing `/`.
+#[derive(Debug, Clone)]
+pub enum AdminAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for AdminAddr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse() {
+            Ok(addr) => Ok(AdminAddr::Tcp(addr)),
+            Err(_) if s.contains('/') => Ok(AdminAddr::Unix(PathBuf::from(s))),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+// Serve the admin HTTP endpoints:
+//
+//     GET  /metrics               Prometheus text format
+//     GET  /sessions              live sessions with byte counters and age
+//     GET  /users                 per-user totals
+//     GET  /stats                 process-wide counters
+//     POST /reload                re-read the config file
+//     POST /sessions/<id>/kill
+//     POST /users/<name>/kill     every session of that user, name percent-encoded
+//     GET  /log-level
+//     POST /log-level/<level>     off, error, warn, info, debug or trace
+//
+// With a token every request needs `Authorization: Bearer <token>`.
+pub async fn serve(listener: Listener, token: Option<String>, shared: Arc<Shared>) -> Result<()> {
+    let token: Option<Arc<str>> = token.map(Into::into);
+    match listener {
+        Listener::Tcp(listener) => loop {
+            let (socket, peer) = listener.accept().await?;
+            spawn(socket, peer.to_string(), token.clone(), shared.clone());
+        },
+        #[cfg(unix)]
+        Listener::Unix(listener, path) => loop {
+            let (socket, _) = listener.accept().await?;
+            spawn(socket, path.display().to_string(), token.clone(), shared.clone());
+        },
+    }
+}
+
+// A bound admin listener, served by `serve`.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, PathBuf),
+}
+
+// Bind the admin listener. Without a token only a loopback address or a Unix
+// socket is accepted, anything else would let other hosts kill sessions.
+pub async fn bind(addr: &AdminAddr, token: Option<&str>) -> Result<Listener> {
+    match addr {
+        AdminAddr::Tcp(addr) if token.is_none() && !addr.ip().is_loopback() => Err(anyhow::anyhow!(
+            "admin API on {} would be reachable from other hosts; bind it to a loopback address or a Unix socket, or set {}",
+            addr, TOKEN_ENV,
+        )),
+        AdminAddr::Tcp(addr) => {
+            let listener = TcpListener::bind(addr).await?;
+            info!("admin listening on {}", listener.local_addr()?);
+            Ok(Listener::Tcp(listener))
+        },
+        #[cfg(unix)]
+        AdminAddr::Unix(path) => {
+            match std::fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {},
+            }
+            let listener = tokio::net::UnixListener::bind(path)?;
+            info!("admin listening on {}", path.display());
+            Ok(Listener::Unix(listener, path.clone()))
+        },
+        #[cfg(not(unix))]
+        AdminAddr::Unix(_) => Err(anyhow::anyhow!("admin Unix sockets are only supported on Unix")),
+    }
+}
+
+fn spawn<T: AsyncRead + AsyncWrite + Unpin + Send + 'static>(mut socket: T, peer: String, token: Option<Arc<str>>, shared: Arc<Shared>) {
+    tokio::spawn(async move {
+        if let Err(e) = handle(&mut socket, token.as_deref(), &shared).await {
+            debug!("admin request from {} failed: {}", peer, e);
+        }
+    });
+}
+
+#[derive(Serialize)]
+struct SessionInfo {
+    id: u64,
+    client: SocketAddr,
+    user: Option<String>,
+    command: Option<&'static str>,
+    destination: Option<String>,
+    route: Option<String>,
+    bytes_up: u64,
+    bytes_down: u64,
+    age_secs: u64,
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn text(status: &'static str, body: &str) -> Self {
+        Response { status, content_type: "text/plain", body: format!("{}\n", body) }
+    }
+
+    fn json<S: Serialize>(value: &S) -> Self {
+        match serde_json::to_string(value) {
+            Ok(body) => Response { status: "200 OK", content_type: "application/json", body: format!("{}\n", body) },
+            Err(e) => Response::text("500 Internal Server Error", &e.to_string()),
+        }
+    }
+}
+
+fn route(method: &str, path: &str, shared: &Shared) -> Response {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        ("GET", ["metrics"]) => Response {
+            status: "200 OK",
+            content_type: "text/plain; version=0.0.4",
+            body: METRICS.render(),
+        },
+        ("GET", ["sessions"]) => {
+            let sessions: Vec<SessionInfo> = REGISTRY.sessions().iter()
+                .map(|stats| {
+                    let details = stats.details();
+                    SessionInfo {
+                        id: stats.id,
+                        client: stats.client,
+                        user: details.user.clone(),
+                        command: details.command,
+                        destination: details.destination.clone(),
+                        route: details.route.clone(),
+                        bytes_up: stats.bytes_up.load(Ordering::Relaxed),
+                        bytes_down: stats.bytes_down.load(Ordering::Relaxed),
+                        age_secs: stats.started.elapsed().as_secs(),
+                    }
+                })
+                .collect();
+            Response::json(&sessions)
+        },
+        ("GET", ["users"]) => Response::json(&REGISTRY.users()),
+        ("GET", ["stats"]) => Response::json(&METRICS.summary()),
+        ("POST", ["reload"]) => match shared.reload() {
+            Ok(()) => Response::text("200 OK", "reloaded"),
+            Err(e) => Response::text("500 Internal Server Error", &format!("{:#}", e)),
+        },
+        ("POST", ["sessions", id, "kill"]) => match id.parse() {
+            Ok(id) if REGISTRY.kill(id) => {
+                info!("admin killed session {}", id);
+                Response::text("200 OK", "killed")
+            },
+            Ok(_) => Response::text("404 Not Found", "no such session"),
+            Err(_) => Response::text("400 Bad Request", "bad session id"),
+        },
+        ("POST", ["users", user, "kill"]) => match percent_decode(user) {
+            Some(user) => {
+                let killed = REGISTRY.kill_user(&user);
+                info!("admin killed {} sessions of {}", killed, user);
+                Response::json(&serde_json::json!({ "killed": killed }))
+            },
+            None => Response::text("400 Bad Request", "bad user name"),
+        },
+        ("GET", ["log-level"]) => Response::text("200 OK", &logging::level()),
+        ("POST", ["log-level", level]) => match level.parse::<LevelFilter>() {
+            Ok(level) => {
+                logging::set_level(level);
+                info!("admin set log level to {}", level);
+                Response::text("200 OK", &level.to_string().to_lowercase())
+            },
+            Err(_) => Response::text("400 Bad Request", "unknown log level"),
+        },
+        ("GET", _) | ("POST", _) => Response::text("404 Not Found", "not found"),
+        _ => Response::text("405 Method Not Allowed", "method not allowed"),
+    }
+}
+
+// A path segment with its %XX escapes decoded, so that user names may hold
+// any character. None when an escape is malformed or the result not UTF-8.
+fn percent_decode(segment: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(segment.len());
+    let mut rest = segment.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b != b'%' {
+            bytes.push(b);
+            rest = tail;
+            continue;
+        }
+        let hex = tail.get(..2).filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))?;
+        bytes.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+        rest = &tail[2..];
+    }
+    String::from_utf8(bytes).ok()
+}
+
+// Whether the head carries `Authorization: Bearer <token>`, compared in
+// constant time. Any request is when there is no token.
+fn authorized(head: &str, token: Option<&str>) -> bool {
+    let token = match token {
+        Some(token) => token.as_bytes(),
+        None => return true,
+    };
+    head.split("\r\n").skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .filter(|(name, _)| name.trim().eq_ignore_ascii_case("authorization"))
+        .filter_map(|(_, value)| value.trim().split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+        .any(|(_, given)| {
+            let given = given.trim().as_bytes();
+            given.len() == token.len() && given.iter().zip(token).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+        })
+}
+
+async fn handle<T: AsyncRead + AsyncWrite + Unpin>(socket: &mut T, token: Option<&str>, shared: &Shared) -> std::io::Result<()> {
+    let head = tokio::time::timeout(HEAD_TIMEOUT, read_head(socket)).await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "no request in time"))??;
+    let mut request_line = head.lines().next().unwrap_or_default().split_whitespace();
+    let (method, path) = (request_line.next().unwrap_or_default(), request_line.next().unwrap_or_default());
+    let (response, challenge) = match authorized(&head, token) {
+        true => (route(method, path, shared), ""),
+        false => (Response::text("401 Unauthorized", "admin token required"), "WWW-Authenticate: Bearer\r\n"),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+        response.status, response.content_type, response.body.len(), challenge, response.body,
+    );
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Extensions;
+    use tokio::io::AsyncReadExt;
+
+    fn shared() -> Shared {
+        Shared::load(None, None, Default::default(), Arc::new(Extensions::default())).unwrap()
+    }
+
+    async fn exchange(request: &[u8]) -> String {
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+        client.write_all(request).await.unwrap();
+        handle(&mut server, None, &shared()).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn requests() {
+        assert!(exchange(b"GET /stats HTTP/1.1\r\n\r\n").await.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(exchange(b"GET /nothing HTTP/1.1\r\n\r\n").await.starts_with("HTTP/1.1 404"));
+        assert!(exchange(b"DELETE /stats HTTP/1.1\r\n\r\n").await.starts_with("HTTP/1.1 405"));
+        assert!(exchange(b"POST /users/john%20doe/kill HTTP/1.1\r\n\r\n").await.ends_with("{\"killed\":0}\n"));
+        assert!(exchange(b"POST /users/%zz/kill HTTP/1.1\r\n\r\n").await.starts_with("HTTP/1.1 400"));
+    }
+
+    #[test]
+    fn user_names() {
+        assert_eq!(percent_decode("alice").as_deref(), Some("alice"));
+        assert_eq!(percent_decode("john%20doe").as_deref(), Some("john doe"));
+        assert_eq!(percent_decode("a%2Fb%25").as_deref(), Some("a/b%"));
+        assert_eq!(percent_decode("%C3%A9").as_deref(), Some("é"));
+        for bad in ["%", "%2", "%zz", "%+1", "%FF"] {
+            assert_eq!(percent_decode(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn tokens() {
+        let head = "GET /stats HTTP/1.1\r\nHost: localhost\r\nauthorization: bearer s3cret\r\n\r\n";
+        assert!(authorized(head, Some("s3cret")));
+        assert!(authorized(head, None));
+        assert!(!authorized(head, Some("s3cre")));
+        assert!(!authorized(head, Some("other1")));
+        assert!(!authorized("GET /stats HTTP/1.1\r\nAuthorization: Basic s3cret\r\n\r\n", Some("s3cret")));
+        assert!(!authorized("GET /stats HTTP/1.1\r\n\r\n", Some("s3cret")));
+    }
+
+    #[tokio::test]
+    async fn only_loopback_without_token() {
+        let public = AdminAddr::Tcp("0.0.0.0:0".parse().unwrap());
+        assert!(bind(&public, None).await.is_err());
+        assert!(bind(&public, Some("s3cret")).await.is_ok());
+        assert!(bind(&AdminAddr::Tcp("127.0.0.1:0".parse().unwrap()), None).await.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_requests_time_out() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(b"GET /stats HTTP/1.1\r\n").await.unwrap();
+        let e = handle(&mut server, None, &shared()).await.unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::TimedOut);
+    }
+}
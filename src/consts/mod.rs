@@ -1,4 +1,5 @@
 #![allow(dead_code)]
+pub const SOCKS4_VERSION:                          u8 = 0x04;
 pub const SOCKS5_VERSION:                          u8 = 0x05;
 
 pub const SOCKS5_AUTH_METHOD_NONE:                 u8 = 0x00;
@@ -10,6 +11,10 @@ pub const SOCKS5_CMD_TCP_CONNECT:                  u8 = 0x01;
 pub const SOCKS5_CMD_TCP_BIND:                     u8 = 0x02;
 pub const SOCKS5_CMD_UDP_ASSOCIATE:                u8 = 0x03;
 
+// RSV flag of a UDP ASSOCIATE request: carry the datagrams on the TCP connection.
+// Non-standard, RFC 1928 defines RSV as zero.
+pub const SOCKS5_FLAG_UDP_OVER_TCP:                u8 = 0x01;
+
 pub const SOCKS5_ADDR_TYPE_IPV4:                   u8 = 0x01;
 pub const SOCKS5_ADDR_TYPE_DOMAIN_NAME:            u8 = 0x03;
 pub const SOCKS5_ADDR_TYPE_IPV6:                   u8 = 0x04;
@@ -22,4 +27,14 @@ pub const SOCKS5_REPLY_HOST_UNREACHABLE:           u8 = 0x04;
 pub const SOCKS5_REPLY_CONNECTION_REFUSED:         u8 = 0x05;
 pub const SOCKS5_REPLY_TTL_EXPIRED:                u8 = 0x06;
 pub const SOCKS5_REPLY_COMMAND_NOT_SUPPORTED:      u8 = 0x07;
-pub const SOCKS5_REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
\ No newline at end of file
+pub const SOCKS5_REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+pub const SOCKS5_AUTH_PASSWORD_VERSION:            u8 = 0x01;
+pub const SOCKS5_AUTH_PASSWORD_SUCCEEDED:          u8 = 0x00;
+pub const SOCKS5_AUTH_PASSWORD_FAILED:             u8 = 0x01;
+
+pub const SOCKS4_REPLY_VERSION:                    u8 = 0x00;
+pub const SOCKS4_REPLY_GRANTED:                    u8 = 0x5a;
+pub const SOCKS4_REPLY_REJECTED:                   u8 = 0x5b;
+pub const SOCKS4_REPLY_IDENTD_UNREACHABLE:         u8 = 0x5c;
+pub const SOCKS4_REPLY_IDENTD_MISMATCH:            u8 = 0x5d;
@@ -0,0 +1,150 @@
+use crate::admin::{AdminAddr, TOKEN_ENV};
+use crate::http::read_head;
+use clap::Subcommand;
+use serde_json::Value;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use anyhow::{anyhow, Context, Result};
+
+#[derive(clap::Args, Debug)]
+pub struct CtlArgs {
+    /// Admin API of the running server, the --admin it was started with
+    #[arg(long, value_name = "PATH|ADDR")]
+    admin: AdminAddr,
+
+    #[command(subcommand)]
+    command: CtlCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum CtlCommand {
+    /// List live sessions
+    Sessions,
+    /// Close a session
+    Kill {
+        /// Session ID, as listed by `sessions`
+        id: u64,
+    },
+    /// Show process-wide counters
+    Stats,
+    /// Re-read the config file
+    Reload,
+    /// Show per-user totals
+    Users,
+}
+
+pub async fn run(args: CtlArgs) -> Result<()> {
+    match args.command {
+        CtlCommand::Sessions => {
+            let sessions = request_json(&args.admin, "GET", "/sessions").await?;
+            let rows = sessions.as_array().into_iter().flatten()
+                .map(|s| ["id", "client", "user", "command", "destination", "route", "bytes_up", "bytes_down", "age_secs"]
+                    .iter()
+                    .map(|key| field(&s[key]))
+                    .collect())
+                .collect();
+            print_table(&["ID", "CLIENT", "USER", "COMMAND", "DESTINATION", "ROUTE", "UP", "DOWN", "AGE"], rows);
+        },
+        CtlCommand::Kill { id } => {
+            print!("{}", request(&args.admin, "POST", &format!("/sessions/{}/kill", id)).await?);
+        },
+        CtlCommand::Stats => {
+            let stats = request_json(&args.admin, "GET", "/stats").await?;
+            print_flat("", &stats);
+        },
+        CtlCommand::Reload => {
+            print!("{}", request(&args.admin, "POST", "/reload").await?);
+        },
+        CtlCommand::Users => {
+            let users = request_json(&args.admin, "GET", "/users").await?;
+            let rows = users.as_object().into_iter().flatten()
+                .map(|(user, totals)| std::iter::once(user.clone())
+                    .chain(["sessions", "active", "bytes_up", "bytes_down"].iter().map(|key| field(&totals[key])))
+                    .collect())
+                .collect();
+            print_table(&["USER", "SESSIONS", "ACTIVE", "UP", "DOWN"], rows);
+        },
+    }
+    Ok(())
+}
+
+fn field(value: &Value) -> String {
+    match value {
+        Value::Null => "-".to_string(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn print_table(header: &[&str], rows: Vec<Vec<String>>) {
+    let mut widths: Vec<usize> = header.iter().map(|h| h.len()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let line = |cells: Vec<&str>| {
+        let padded: Vec<String> = cells.iter().zip(&widths).map(|(c, w)| format!("{:<w$}", c, w = w)).collect();
+        println!("{}", padded.join("  ").trim_end());
+    };
+    line(header.to_vec());
+    for row in &rows {
+        line(row.iter().map(|c| c.as_str()).collect());
+    }
+}
+
+// `key value` lines, nested objects joined with dots.
+fn print_flat(prefix: &str, value: &Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                let key = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+                print_flat(&key, value);
+            }
+        },
+        other => println!("{} {}", prefix, field(other)),
+    }
+}
+
+// The admin API token from $SOCKS_ADMIN_TOKEN, for the server and for `ctl`.
+pub fn admin_token() -> Option<String> {
+    std::env::var(TOKEN_ENV).ok().filter(|token| !token.is_empty())
+}
+
+async fn request_json(admin: &AdminAddr, method: &str, path: &str) -> Result<Value> {
+    let body = request(admin, method, path).await?;
+    serde_json::from_str(&body).context("unexpected response from the admin API")
+}
+
+// Send one request to the admin API and return the body of a 2xx response.
+async fn request(admin: &AdminAddr, method: &str, path: &str) -> Result<String> {
+    match admin {
+        AdminAddr::Tcp(addr) => {
+            let mut stream = TcpStream::connect(addr).await
+                .with_context(|| format!("can not connect to {}", addr))?;
+            exchange(&mut stream, method, path).await
+        },
+        #[cfg(unix)]
+        AdminAddr::Unix(path_buf) => {
+            let mut stream = tokio::net::UnixStream::connect(path_buf).await
+                .with_context(|| format!("can not connect to {}", path_buf.display()))?;
+            exchange(&mut stream, method, path).await
+        },
+        #[cfg(not(unix))]
+        AdminAddr::Unix(_) => Err(anyhow!("admin Unix sockets are only supported on Unix")),
+    }
+}
+
+async fn exchange<T: AsyncRead + AsyncWrite + Unpin>(stream: &mut T, method: &str, path: &str) -> Result<String> {
+    let authorization = admin_token().map(|token| format!("Authorization: Bearer {}\r\n", token)).unwrap_or_default();
+    let request = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n", method, path, authorization);
+    stream.write_all(request.as_bytes()).await?;
+    let head = read_head(stream).await?;
+    let mut body = String::new();
+    stream.read_to_string(&mut body).await?;
+    let status = head.split_whitespace().nth(1).unwrap_or_default();
+    if !status.starts_with('2') {
+        return Err(anyhow!("{} {}: {} {}", method, path, status, body.trim_end()));
+    }
+    Ok(body)
+}
@@ -0,0 +1,129 @@
+// Passing listening sockets to a new process for upgrades without downtime.
+//
+// The running server listens on a Unix socket. A new process started with the
+// same --handover path connects to it and receives every listening socket
+// with SCM_RIGHTS, then acknowledges with one byte. The old process stops
+// accepting and drains; connections that arrive meanwhile wait in the shared
+// accept queue for the new process.
+use nix::sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags};
+use std::io::{ErrorKind, IoSlice, IoSliceMut};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, Interest};
+use tokio::net::{TcpListener, UnixListener, UnixStream};
+use tracing::{debug, info, warn};
+use anyhow::{anyhow, Context, Result};
+
+// Upper bound on the listeners one handover can carry.
+const MAX_FDS: usize = 64;
+
+// Ask the process serving `path` for its listeners. Returns none when no
+// process is serving it.
+pub async fn take(path: &Path) -> Result<Vec<TcpListener>> {
+    let mut stream = match UnixStream::connect(path).await {
+        Ok(stream) => stream,
+        Err(e) if e.kind() == ErrorKind::NotFound || e.kind() == ErrorKind::ConnectionRefused => {
+            debug!("no running server on {}: {}", path.display(), e);
+            return Ok(Vec::new());
+        },
+        Err(e) => return Err(e).with_context(|| format!("can not connect to {}", path.display())),
+    };
+    let fds = stream.async_io(Interest::READABLE, || {
+        let mut byte = [0u8; 1];
+        let mut iov = [IoSliceMut::new(&mut byte)];
+        let mut space = nix::cmsg_space!([RawFd; MAX_FDS]);
+        let msg = recvmsg::<()>(stream.as_raw_fd(), &mut iov, Some(&mut space), MsgFlags::MSG_CMSG_CLOEXEC)?;
+        let mut fds = Vec::new();
+        for cmsg in msg.cmsgs()? {
+            if let ControlMessageOwned::ScmRights(received) = cmsg {
+                fds.extend(received);
+            }
+        }
+        Ok(fds)
+    }).await?;
+    if fds.is_empty() {
+        return Err(anyhow!("handover from {} carried no listeners", path.display()));
+    }
+    let mut listeners = Vec::new();
+    for fd in fds {
+        // SAFETY: the descriptor was just received and nothing else owns it.
+        let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        listener.set_nonblocking(true)?;
+        listeners.push(TcpListener::from_std(listener)?);
+    }
+    stream.write_all(&[1]).await?;
+    info!("took over {} listeners from {}", listeners.len(), path.display());
+    Ok(listeners)
+}
+
+// Serve `path` until a new process takes over `listeners`, then return so
+// this one can drain. Only binding `path` fails; a new process that goes away
+// before acknowledging leaves this one serving, waiting for the next.
+pub async fn serve(path: &Path, listeners: Vec<RawFd>) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+        _ => {},
+    }
+    let server = UnixListener::bind(path)
+        .with_context(|| format!("can not listen on {}", path.display()))?;
+    info!("handover socket listening on {}", path.display());
+    loop {
+        let mut stream = match server.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("handover accept failed: {}", e);
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                continue;
+            },
+        };
+        let sent = stream.async_io(Interest::WRITABLE, || {
+            let rights = [ControlMessage::ScmRights(&listeners)];
+            sendmsg::<()>(stream.as_raw_fd(), &[IoSlice::new(&[0])], &rights, MsgFlags::empty(), None)?;
+            Ok(())
+        }).await;
+        if let Err(e) = sent {
+            warn!("handover failed: {}", e);
+            continue;
+        }
+        let mut ack = [0u8; 1];
+        match stream.read(&mut ack).await {
+            Ok(1) => {
+                info!("listeners handed over through {}", path.display());
+                return Ok(());
+            },
+            Ok(_) => warn!("handover aborted by the new process"),
+            Err(e) => warn!("handover failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn survives_a_peer_that_does_not_ack() {
+        let path = std::env::temp_dir().join(format!("socks-test-{}-handover.sock", std::process::id()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let serving = tokio::spawn({
+            let (path, fd) = (path.clone(), listener.as_raw_fd());
+            async move { serve(&path, vec![fd]).await }
+        });
+        while UnixStream::connect(&path).await.is_err() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        // Connects, and goes away without taking anything.
+        for _ in 0..3 {
+            drop(UnixStream::connect(&path).await.unwrap());
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!serving.is_finished());
+
+        let taken = take(&path).await.unwrap();
+        assert_eq!(taken.len(), 1);
+        assert_eq!(taken[0].local_addr().unwrap(), addr);
+        serving.await.unwrap().unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+}
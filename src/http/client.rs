@@ -0,0 +1,115 @@
+use super::{authority, read_head};
+use crate::consts;
+use crate::socks::client::Credentials;
+use base64::Engine;
+use std::net::IpAddr;
+use base64::engine::general_purpose::STANDARD;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tracing::debug;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum HttpError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("malformed response from http proxy: {0:?}")]
+    Malformed(String),
+    #[error("http proxy answered {0} {1}")]
+    Status(u16, String),
+    #[error("not a valid host name: {0:?}")]
+    InvalidHost(String),
+}
+
+impl HttpError {
+    pub fn reply_code(&self) -> u8 {
+        match self {
+            HttpError::Status(status, _) => reply_code_from_status(*status),
+            HttpError::Io(e) => crate::socks::replies::reply_code_from_io_error(e),
+            HttpError::Malformed(_) => consts::SOCKS5_REPLY_GENERAL_FAILURE,
+            HttpError::InvalidHost(_) => consts::SOCKS5_REPLY_HOST_UNREACHABLE,
+        }
+    }
+}
+
+// Translate the status of a failed CONNECT into the closest SOCKS5 reply field.
+fn reply_code_from_status(status: u16) -> u8 {
+    match status {
+        401 | 403 | 407 => consts::SOCKS5_REPLY_CONNECTION_NOT_ALLOWED,
+        404 | 410 => consts::SOCKS5_REPLY_HOST_UNREACHABLE,
+        405 | 501 => consts::SOCKS5_REPLY_COMMAND_NOT_SUPPORTED,
+        502 => consts::SOCKS5_REPLY_CONNECTION_REFUSED,
+        503 => consts::SOCKS5_REPLY_NETWORK_UNREACHABLE,
+        504 => consts::SOCKS5_REPLY_TTL_EXPIRED,
+        _ => consts::SOCKS5_REPLY_GENERAL_FAILURE,
+    }
+}
+
+// An IP address or a DNS name. Anything else, like a domain from a SOCKS5
+// request carrying CR, LF or spaces, would end up in the request head.
+fn valid_host(host: &str) -> bool {
+    if host.parse::<IpAddr>().is_ok() {
+        return true;
+    }
+    let name = host.strip_suffix('.').unwrap_or(host);
+    !name.is_empty() && name.len() <= 253 && name.split('.').all(|label| {
+        (1..=63).contains(&label.len()) && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    })
+}
+
+// Open a tunnel with `CONNECT host:port` and hand back the stream once the proxy answered 2xx.
+pub async fn connect<T>(mut socket: T, credentials: Option<&Credentials>, host: &str, port: u16) -> Result<T, HttpError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    if !valid_host(host) {
+        return Err(HttpError::InvalidHost(host.to_string()));
+    }
+    let target = authority(host, port);
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+    if let Some(c) = credentials {
+        let token = STANDARD.encode(format!("{}:{}", c.username, c.password));
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+    }
+    request.push_str("\r\n");
+    socket.write_all(request.as_bytes()).await?;
+
+    let head = read_head(&mut socket).await?;
+    let status_line = head.lines().next().unwrap_or_default();
+    debug!("http proxy: {}", status_line);
+    let mut parts = status_line.splitn(3, ' ');
+    let status: u16 = match (parts.next(), parts.next()) {
+        (Some(version), Some(code)) if version.starts_with("HTTP/1.") => code.parse()
+            .map_err(|_| HttpError::Malformed(status_line.to_string()))?,
+        _ => return Err(HttpError::Malformed(status_line.to_string())),
+    };
+    if !(200..300).contains(&status) {
+        return Err(HttpError::Status(status, parts.next().unwrap_or_default().to_string()));
+    }
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_names() {
+        for host in ["example.com", "example.com.", "_dmarc.example-1.com", "10.0.0.1", "::1", "localhost"] {
+            assert!(valid_host(host), "{:?}", host);
+        }
+        let long_label = "a".repeat(64);
+        for host in ["", ".", "a..b", "example.com\r\nX-Injected: 1", "exa mple.com", "example.com:80", "ex\u{7f}ample", "bücher.de", long_label.as_str()] {
+            assert!(!valid_host(host), "{:?}", host);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_host_before_writing() {
+        let (client, mut proxy) = tokio::io::duplex(1024);
+        let res = connect(client, None, "example.com\r\nHost: evil", 443).await;
+        assert!(matches!(res, Err(HttpError::InvalidHost(_))));
+        let mut written = Vec::new();
+        tokio::io::AsyncReadExt::read_to_end(&mut proxy, &mut written).await.unwrap();
+        assert!(written.is_empty());
+    }
+}
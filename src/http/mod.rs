@@ -0,0 +1,29 @@
+pub mod client;
+pub mod server;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// Upper bound for a request or response head, anything larger is treated as malformed.
+const MAX_HEAD_LEN: usize = 16 * 1024;
+
+// Read an HTTP head up to and including the blank line. Reads byte by byte so
+// nothing that belongs to the tunnelled stream is consumed.
+pub async fn read_head<T: AsyncRead + Unpin>(socket: &mut T) -> std::io::Result<String> {
+    let mut head: Vec<u8> = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD_LEN {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "http head too long"));
+        }
+        head.push(socket.read_u8().await?);
+    }
+    Ok(String::from_utf8_lossy(&head).to_string())
+}
+
+// `host:port` as used in a CONNECT request line, with brackets around IPv6 literals.
+pub fn authority(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
@@ -0,0 +1,475 @@
+use super::read_head;
+use crate::config::Config;
+use crate::consts;
+use crate::metrics::METRICS;
+use crate::session::Session;
+use crate::socks::SocksAddress;
+use crate::socks::SocksCommand;
+use crate::socks::handlers::SocksHandler;
+use crate::socks::requests::SocksRequest;
+use crate::timeouts::Phase;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tracing::{debug, info, info_span, Instrument, Span};
+use anyhow::{anyhow, Result};
+
+// Headers that only concern the hop between the client and us.
+const HOP_BY_HOP: [&str; 4] = ["proxy-authorization", "proxy-connection", "connection", "keep-alive"];
+
+// Whether the first bytes of a connection look like an HTTP request line
+// rather than a SOCKS version number.
+pub fn is_http(first: u8) -> bool {
+    first.is_ascii_uppercase()
+}
+
+// The response for a failed request, from the SOCKS5 reply it maps to.
+pub fn response(rep: u8) -> String {
+    let status = match rep {
+        consts::SOCKS5_REPLY_SUCCEEDED => return "HTTP/1.1 200 Connection established\r\n\r\n".to_string(),
+        consts::SOCKS5_REPLY_CONNECTION_NOT_ALLOWED => "403 Forbidden",
+        consts::SOCKS5_REPLY_COMMAND_NOT_SUPPORTED => "405 Method Not Allowed",
+        consts::SOCKS5_REPLY_TTL_EXPIRED => "504 Gateway Timeout",
+        _ => "502 Bad Gateway",
+    };
+    error_response(status, "")
+}
+
+fn error_response(status: &str, extra_headers: &str) -> String {
+    format!("HTTP/1.1 {}\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n", status, extra_headers)
+}
+
+// Serve an HTTP proxy request. `prefix` is what was already read from the
+// socket while telling HTTP apart from SOCKS.
+pub async fn serve<T>(socket: T, prefix: &[u8], session: &mut Session, config: Arc<Config>) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut socket = Prefixed::new(prefix, socket);
+    let head = config.timeouts.run(Phase::Request, read_head(&mut socket))
+        .instrument(info_span!("request", protocol = "http"))
+        .await;
+    let head = match head {
+        Ok(Ok(head)) => head,
+        Ok(Err(e)) => {
+            session.stats.close("client_error");
+            return Err(e.into());
+        },
+        Err(_) => {
+            session.stats.close("request_timeout");
+            return Ok(());
+        },
+    };
+    let request = match Request::parse(&head) {
+        Some(request) => request,
+        None => {
+            reject(&mut socket, session, "400 Bad Request", "", "bad_request").await;
+            return Err(anyhow!("malformed http request {:?}", head.lines().next().unwrap_or_default()));
+        },
+    };
+    debug!("http {} {}", request.method, request.target);
+
+    // Clients authenticated by their TLS certificate already have a user.
+    if session.user.is_none() && config.password_required() {
+        let user = match request.credentials() {
+            Some((user, password)) if config.authenticate(&user, &password).await => Some(user),
+            _ => None,
+        };
+        match user {
+            Some(user) => {
+                Span::current().record("user", user.as_str());
+                session.set_user(user);
+            },
+            None => {
+                METRICS.handshake("http", "auth_failed".to_string());
+                info!("proxy authentication failed for {}", session.client_ip_port);
+                reject(
+                    &mut socket,
+                    session,
+                    "407 Proxy Authentication Required",
+                    "Proxy-Authenticate: Basic realm=\"socks\"\r\n",
+                    "auth_failed",
+                ).await;
+                return Ok(());
+            },
+        }
+    }
+
+    if request.method == "CONNECT" {
+        let (dst_address, dst_port) = match parse_authority(request.target, None) {
+            Some(dst) => dst,
+            None => {
+                reject(&mut socket, session, "400 Bad Request", "", "bad_request").await;
+                return Err(anyhow!("bad CONNECT target {:?}", request.target));
+            },
+        };
+        let socks_request = SocksRequest::new(SocksCommand::TCPConnect, dst_address, dst_port);
+        return SocksHandler::http(socket, socks_request, session.clone(), config).execute_command().await;
+    }
+
+    // Forward proxying needs the absolute form, `GET http://host/path`.
+    let (dst, path) = match request.target.strip_prefix("http://") {
+        Some(rest) => match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        },
+        None => {
+            reject(&mut socket, session, "400 Bad Request", "", "bad_request").await;
+            return Err(anyhow!("not a proxy request: {} {}", request.method, request.target));
+        },
+    };
+    // Credentials in the URL are not passed on.
+    let host = dst.rsplit('@').next().unwrap_or_default();
+    let (dst_address, dst_port) = match parse_authority(host, Some(80)) {
+        Some(dst) => dst,
+        None => {
+            reject(&mut socket, session, "400 Bad Request", "", "bad_request").await;
+            return Err(anyhow!("bad forward target {:?}", request.target));
+        },
+    };
+    let framing = match request.framing() {
+        Some(framing) => framing,
+        None => {
+            reject(&mut socket, session, "400 Bad Request", "", "bad_request").await;
+            return Err(anyhow!("request body of {} {} has no usable length", request.method, request.target));
+        },
+    };
+    let head = request.origin_head(path, host);
+    let socks_request = SocksRequest::new(SocksCommand::TCPConnect, dst_address, dst_port);
+    // Only this request reaches the target: the client's next requests were
+    // never routed, so nothing after the body is read.
+    let socket = Body::new(socket, framing);
+    SocksHandler::http(socket, socks_request, session.clone(), config).forward(head.as_bytes()).await
+}
+
+async fn reject<T: AsyncWrite + Unpin>(socket: &mut T, session: &Session, status: &str, extra_headers: &str, close: &'static str) {
+    session.stats.close(close);
+    if let Err(e) = socket.write_all(error_response(status, extra_headers).as_bytes()).await {
+        debug!("failed to answer http client: {}", e);
+    }
+}
+
+struct Request<'a> {
+    method: &'a str,
+    target: &'a str,
+    version: &'a str,
+    headers: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> Request<'a> {
+    fn parse(head: &'a str) -> Option<Self> {
+        let mut lines = head.split("\r\n");
+        let mut request_line = lines.next()?.split(' ');
+        let (method, target, version) = (request_line.next()?, request_line.next()?, request_line.next()?);
+        if !version.starts_with("HTTP/1.") {
+            return None;
+        }
+        let headers = lines
+            .filter(|line| !line.is_empty())
+            .map(|line| line.split_once(':').map(|(name, value)| (name.trim(), value.trim())))
+            .collect::<Option<Vec<_>>>()?;
+        Some(Request { method, target, version, headers })
+    }
+
+    fn header(&self, name: &str) -> Option<&'a str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| *v)
+    }
+
+    // Username and password from `Proxy-Authorization: Basic ...`.
+    fn credentials(&self) -> Option<(String, String)> {
+        let value = self.header("proxy-authorization")?;
+        let (scheme, token) = value.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let decoded = String::from_utf8(STANDARD.decode(token.trim()).ok()?).ok()?;
+        let (user, password) = decoded.split_once(':')?;
+        Some((user.to_string(), password.to_string()))
+    }
+
+    // How the body after the head is delimited, None when that can not be
+    // told for sure, like with conflicting lengths.
+    fn framing(&self) -> Option<Framing> {
+        let lengths: Vec<_> = self.headers.iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            .map(|(_, value)| value.parse::<u64>().ok())
+            .collect::<Option<_>>()?;
+        match self.header("transfer-encoding") {
+            // Both at once is how requests get smuggled past proxies.
+            Some(_) if !lengths.is_empty() => None,
+            Some(coding) if coding.rsplit(',').next()?.trim().eq_ignore_ascii_case("chunked") => {
+                Some(Framing::Chunked(Chunked::Size(Vec::new())))
+            },
+            Some(_) => None,
+            None => match lengths.split_first() {
+                Some((first, rest)) if rest.iter().all(|n| n == first) => Some(Framing::Length(*first)),
+                Some(_) => None,
+                None => Some(Framing::Length(0)),
+            },
+        }
+    }
+
+    // The head to send to the target: origin-form request line, our own hop
+    // headers dropped, and one request per connection.
+    fn origin_head(&self, path: &str, host: &str) -> String {
+        let mut head = format!("{} {} {}\r\n", self.method, path, self.version);
+        if self.header("host").is_none() {
+            head.push_str(&format!("Host: {}\r\n", host));
+        }
+        for (name, value) in &self.headers {
+            if !HOP_BY_HOP.iter().any(|h| name.eq_ignore_ascii_case(h)) {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+        }
+        head.push_str("Connection: close\r\n\r\n");
+        head
+    }
+}
+
+// `host:port`, or `host` when a default port is given. IPv6 literals are in brackets.
+fn parse_authority(s: &str, default_port: Option<u16>) -> Option<(SocksAddress, u16)> {
+    let (host, port) = match s.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => (host, Some(port.parse().ok()?)),
+        _ => (s, None),
+    };
+    let port = port.or(default_port)?;
+    let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+    if host.is_empty() {
+        return None;
+    }
+    let address = match host.parse() {
+        Ok(ip) => SocksAddress::IP(ip),
+        Err(_) => SocksAddress::Domain(host.to_string()),
+    };
+    Some((address, port))
+}
+
+// Where the request body ends.
+#[derive(Debug, PartialEq)]
+enum Framing {
+    Length(u64),
+    Chunked(Chunked),
+}
+
+// Position in a chunked body.
+#[derive(Debug, PartialEq)]
+enum Chunked {
+    // In a chunk-size line, read so far.
+    Size(Vec<u8>),
+    // In chunk data, bytes left including the CRLF after it.
+    Data(u64),
+    // In the trailer section after the last chunk, the current line so far.
+    Trailer(Vec<u8>),
+}
+
+// Longest chunk-size or trailer line accepted.
+const MAX_LINE_LEN: usize = 4096;
+
+// The client side of a forwarded request: reads end with the request body,
+// writes go through.
+struct Body<S> {
+    inner: S,
+    framing: Framing,
+}
+
+impl<S> Body<S> {
+    fn new(inner: S, framing: Framing) -> Self {
+        Body { inner, framing }
+    }
+
+    // How much may be read next without going past the body; lines are read
+    // byte by byte.
+    fn limit(&self) -> u64 {
+        match &self.framing {
+            Framing::Length(n) | Framing::Chunked(Chunked::Data(n)) => *n,
+            Framing::Chunked(_) => 1,
+        }
+    }
+
+    // Account for `bytes` read from the body.
+    fn consume(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        let invalid = |msg| std::io::Error::new(std::io::ErrorKind::InvalidData, msg);
+        self.framing = match std::mem::replace(&mut self.framing, Framing::Length(0)) {
+            Framing::Length(n) => Framing::Length(n - bytes.len() as u64),
+            Framing::Chunked(Chunked::Data(n)) => match n - bytes.len() as u64 {
+                0 => Framing::Chunked(Chunked::Size(Vec::new())),
+                n => Framing::Chunked(Chunked::Data(n)),
+            },
+            Framing::Chunked(Chunked::Size(mut line)) => {
+                line.extend_from_slice(bytes);
+                match line.strip_suffix(b"\r\n") {
+                    Some(line) => {
+                        // Extensions after `;` are passed on, not interpreted.
+                        let size = String::from_utf8_lossy(line);
+                        let size = size.split(';').next().unwrap_or_default().trim();
+                        match u64::from_str_radix(size, 16) {
+                            Ok(0) => Framing::Chunked(Chunked::Trailer(Vec::new())),
+                            Ok(size) => Framing::Chunked(Chunked::Data(size.checked_add(2).ok_or_else(|| invalid("chunk too large"))?)),
+                            Err(_) => return Err(invalid("bad chunk size")),
+                        }
+                    },
+                    None if line.len() >= MAX_LINE_LEN => return Err(invalid("chunk size line too long")),
+                    None => Framing::Chunked(Chunked::Size(line)),
+                }
+            },
+            Framing::Chunked(Chunked::Trailer(mut line)) => {
+                line.extend_from_slice(bytes);
+                match line.ends_with(b"\r\n") {
+                    // The empty line ends the body.
+                    true if line.len() == 2 => Framing::Length(0),
+                    true => Framing::Chunked(Chunked::Trailer(Vec::new())),
+                    false if line.len() >= MAX_LINE_LEN => return Err(invalid("trailer line too long")),
+                    false => Framing::Chunked(Chunked::Trailer(line)),
+                }
+            },
+        };
+        Ok(())
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Body<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let limit = self.limit().min(buf.remaining() as u64) as usize;
+        if limit == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(limit));
+        ready!(Pin::new(&mut self.inner).poll_read(cx, &mut limited))?;
+        let n = limited.filled().len();
+        if n == 0 {
+            return Poll::Ready(Err(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+        self.consume(&buf.initialize_unfilled()[..n])?;
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Body<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+// A stream with bytes that were already read put back in front of it.
+struct Prefixed<S> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S> Prefixed<S> {
+    fn new(prefix: &[u8], inner: S) -> Self {
+        Prefixed { prefix: prefix.to_vec(), pos: 0, inner }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Prefixed<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        if self.pos < self.prefix.len() {
+            let n = buf.remaining().min(self.prefix.len() - self.pos);
+            buf.put_slice(&self.prefix[self.pos..self.pos + n]);
+            self.pos += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Prefixed<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn request_head() {
+        let head = "GET http://example.com/a?b HTTP/1.1\r\nHost: example.com\r\nProxy-Authorization: Basic YWxpY2U6c2VjcmV0\r\nProxy-Connection: keep-alive\r\nAccept: */*\r\n\r\n";
+        let request = Request::parse(head).unwrap();
+        assert_eq!((request.method, request.target, request.version), ("GET", "http://example.com/a?b", "HTTP/1.1"));
+        assert_eq!(request.header("accept"), Some("*/*"));
+        assert_eq!(request.credentials(), Some(("alice".to_string(), "secret".to_string())));
+        assert_eq!(
+            request.origin_head("/a?b", "example.com"),
+            "GET /a?b HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\nConnection: close\r\n\r\n",
+        );
+        assert!(Request::parse("GET / SPDY/3\r\n\r\n").is_none());
+        assert!(Request::parse("GET http://example.com/ HTTP/1.1\r\nno colon\r\n\r\n").is_none());
+    }
+
+    #[test]
+    fn authorities() {
+        assert!(matches!(parse_authority("example.com:8080", None), Some((SocksAddress::Domain(host), 8080)) if host == "example.com"));
+        assert!(matches!(parse_authority("[::1]", Some(80)), Some((SocksAddress::IP(ip), 80)) if ip.is_loopback()));
+        assert!(parse_authority("example.com", None).is_none());
+        assert!(parse_authority(":80", None).is_none());
+    }
+
+    fn framing(headers: &str) -> Option<Framing> {
+        Request::parse(&format!("POST http://example.com/ HTTP/1.1\r\n{}\r\n", headers)).unwrap().framing()
+    }
+
+    #[test]
+    fn body_framing() {
+        assert_eq!(framing(""), Some(Framing::Length(0)));
+        assert_eq!(framing("Content-Length: 5\r\n"), Some(Framing::Length(5)));
+        assert_eq!(framing("Content-Length: 5\r\nContent-Length: 5\r\n"), Some(Framing::Length(5)));
+        assert_eq!(framing("Transfer-Encoding: gzip, chunked\r\n"), Some(Framing::Chunked(Chunked::Size(Vec::new()))));
+        assert_eq!(framing("Content-Length: 5\r\nContent-Length: 6\r\n"), None);
+        assert_eq!(framing("Content-Length: -1\r\n"), None);
+        assert_eq!(framing("Transfer-Encoding: chunked\r\nContent-Length: 5\r\n"), None);
+        assert_eq!(framing("Transfer-Encoding: gzip\r\n"), None);
+    }
+
+    // What a forwarded request lets through of `input`.
+    async fn body(framing: Framing, input: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut body = Body::new(input, framing);
+        let mut read = Vec::new();
+        body.read_to_end(&mut read).await?;
+        Ok(read)
+    }
+
+    #[tokio::test]
+    async fn body_stops_before_the_next_request() {
+        let next = b"GET http://elsewhere/ HTTP/1.1\r\n\r\n";
+        assert_eq!(body(Framing::Length(5), &[&b"hello"[..], next].concat()).await.unwrap(), b"hello");
+        assert_eq!(body(Framing::Length(0), next).await.unwrap(), b"");
+        let chunked = b"5;name=x\r\nhello\r\n6\r\n world\r\n0\r\nX-Trailer: 1\r\n\r\n";
+        let framing = || Framing::Chunked(Chunked::Size(Vec::new()));
+        assert_eq!(body(framing(), &[&chunked[..], next].concat()).await.unwrap(), chunked);
+        assert_eq!(body(framing(), b"0\r\n\r\nGET").await.unwrap(), b"0\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn malformed_bodies() {
+        let framing = || Framing::Chunked(Chunked::Size(Vec::new()));
+        assert_eq!(body(framing(), b"zz\r\n").await.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(body(framing(), &[b'1'; MAX_LINE_LEN]).await.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(body(framing(), b"5\r\nhel").await.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(body(Framing::Length(5), b"hel").await.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,34 @@
+//! The proxy as a library: `Server::builder()` starts one inside another
+//! program, the `socks` binary is a command line around it. See `Server` for
+//! the state shared by every server in a process.
+mod access;
+mod admin;
+mod config;
+mod consts;
+#[doc(hidden)]
+pub mod ctl;
+#[cfg(unix)]
+mod handover;
+mod http;
+#[doc(hidden)]
+pub mod logging;
+mod metrics;
+mod outbound;
+mod proxy_protocol;
+mod router;
+mod server;
+mod session;
+mod shutdown;
+mod socks;
+mod timeouts;
+mod tls;
+mod transport;
+mod tunnel;
+mod upstream;
+
+pub use admin::AdminAddr;
+pub use server::{Acl, Authenticator, Builder, Closed, Connector, Handle, Hooks, Request, Resolver, Server};
+pub use socks::traits::{AsyncStream, BoxStream};
+pub use upstream::Hop;
+#[doc(hidden)]
+pub use shutdown::signal;
@@ -0,0 +1,108 @@
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::TracerProvider;
+use opentelemetry_sdk::{runtime, Resource};
+use std::io::IsTerminal;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, Layer, Registry};
+use anyhow::{anyhow, Error, Result};
+
+// Where finished spans are exported.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Exporter {
+    // Human-readable dump of every finished span on stdout.
+    Stdout,
+    // OTLP over gRPC.
+    Otlp,
+}
+
+impl FromStr for Exporter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stdout" => Ok(Exporter::Stdout),
+            "otlp" => Ok(Exporter::Otlp),
+            _ => Err(anyhow!("unknown trace exporter {:?}", s)),
+        }
+    }
+}
+
+type FilterHandle = reload::Handle<EnvFilter, Registry>;
+
+static FILTER: OnceLock<FilterHandle> = OnceLock::new();
+static PROVIDER: OnceLock<TracerProvider> = OnceLock::new();
+
+// Log to stderr as configured by RUST_LOG, or at `default` when it is not set,
+// and export spans when an exporter is given. Records from the `log` crate are
+// forwarded too.
+pub fn init(default: &str, exporter: Option<Exporter>, otlp_endpoint: Option<&str>) -> Result<()> {
+    let filter = EnvFilter::try_from_default_env().or_else(|_| EnvFilter::try_new(default))?;
+    let (filter, handle) = reload::Layer::new(filter);
+    let provider = match exporter {
+        None => None,
+        Some(Exporter::Stdout) => Some(TracerProvider::builder()
+            .with_simple_exporter(opentelemetry_stdout::SpanExporter::default())
+            .with_resource(resource())
+            .build()),
+        Some(Exporter::Otlp) => {
+            let mut builder = opentelemetry_otlp::SpanExporter::builder().with_tonic();
+            if let Some(endpoint) = otlp_endpoint {
+                builder = builder.with_endpoint(endpoint);
+            }
+            Some(TracerProvider::builder()
+                .with_batch_exporter(builder.build()?, runtime::Tokio)
+                .with_resource(resource())
+                .build())
+        },
+    };
+    // Spans are exported whatever the log level is.
+    let otel = provider.as_ref().map(|provider| {
+        tracing_opentelemetry::layer()
+            .with_tracer(provider.tracer("socks"))
+            .with_filter(LevelFilter::INFO)
+    });
+    tracing_subscriber::registry()
+        .with(fmt::layer()
+            .with_writer(std::io::stderr)
+            .with_ansi(std::io::stderr().is_terminal())
+            .with_filter(filter))
+        .with(otel)
+        .try_init()?;
+    let _ = FILTER.set(handle);
+    if let Some(provider) = provider {
+        let _ = PROVIDER.set(provider);
+    }
+    Ok(())
+}
+
+fn resource() -> Resource {
+    Resource::new([KeyValue::new("service.name", "socks")])
+}
+
+// Replace the log filter with a single level for every module.
+pub fn set_level(level: LevelFilter) {
+    if let Some(handle) = FILTER.get() {
+        let _ = handle.reload(EnvFilter::default().add_directive(level.into()));
+    }
+}
+
+pub fn level() -> String {
+    FILTER.get()
+        .and_then(|handle| handle.with_current(|filter| filter.to_string()).ok())
+        .unwrap_or_default()
+}
+
+// Flush spans that are still queued for export.
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get() {
+        if let Err(e) = provider.shutdown() {
+            eprintln!("can not flush traces: {}", e);
+        }
+    }
+}
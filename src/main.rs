@@ -1,17 +1,83 @@
-use log::{debug, info, error};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use std::net::SocketAddr;
-use clap::Parser;
-mod consts;
-mod socks;
-
-use socks::handlers::{SocksHandler, MethodHandler};
+use tracing::{error, info};
+use std::path::PathBuf;
+use clap::{Parser, Subcommand};
+use socks::{ctl, logging, signal, AdminAddr, Hop, Server};
 use anyhow::Result;
 
 /// A SOCKS5 proxy server
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    // Options given without a subcommand run the server, as `serve` does.
+    #[command(flatten)]
+    serve: Args,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run the proxy server (the default)
+    Serve(Args),
+    /// Talk to a running server through its admin API
+    Ctl(ctl::CtlArgs),
+    /// Serve SOCKS5 locally and send every session through a remote instance's tunnel
+    Local(LocalArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct LocalArgs {
+    /// Host address to bind
+    #[arg(long, default_value = "127.0.0.1")]
+    host: String,
+
+    /// Port number to listen on
+    #[arg(long, default_value_t = 1080)]
+    port: u16,
+
+    /// Enable verbose mode
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// Tunnel listener of the remote instance, tunnel://[user:password@]host:port[?connections=N] or quic://[user:password@]host:port
+    #[arg(long, value_name = "URL")]
+    remote: Hop,
+
+    /// TOML file with local users, rules and the like; unmatched requests go to the remote
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Admin HTTP API (metrics, sessions, log level) on host:port or a Unix socket path; a non-loopback address needs SOCKS_ADMIN_TOKEN
+    #[arg(long, value_name = "ADDR|PATH")]
+    admin: Option<AdminAddr>,
+}
+
+impl LocalArgs {
+    // A local instance is a server whose default route is the tunnel.
+    fn into_args(self) -> Result<Args> {
+        if !self.remote.is_tunnel() {
+            return Err(anyhow::anyhow!("--remote must be a tunnel:// or quic:// URL"));
+        }
+        // Only a multiplexed tunnel can carry UDP, otherwise it is served here.
+        let upstream_udp = self.remote.is_multiplexed();
+        Ok(Args {
+            host: self.host,
+            port: self.port,
+            verbose: self.verbose,
+            upstreams: vec![self.remote],
+            upstream_udp,
+            direct_udp: !upstream_udp,
+            config: self.config,
+            admin: self.admin,
+            trace: None,
+            otlp_endpoint: None,
+            handover: None,
+        })
+    }
+}
+
+#[derive(clap::Args, Debug)]
 struct Args {
     /// Host address to bind
     #[arg(long, default_value = "127.0.0.1")]
@@ -24,74 +90,92 @@ struct Args {
     /// Enable verbose mode
     #[arg(short, long)]
     verbose: bool,
+
+    /// Parent proxy, socks5://[user:password@]host:port, http://[user:password@]host:port or ws[s]://[user:password@]host:port/path. Repeat to build a chain, in order.
+    #[arg(long = "upstream", value_name = "URL")]
+    upstreams: Vec<Hop>,
+
+    /// Also forward UDP ASSOCIATE through the last upstream
+    #[arg(long)]
+    upstream_udp: bool,
+
+    /// Without --upstream-udp, serve UDP ASSOCIATE routed to an upstream directly instead of refusing it
+    #[arg(long)]
+    direct_udp: bool,
+
+    /// TOML file with users, named upstreams and routing rules
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Admin HTTP API (metrics, sessions, log level) on host:port or a Unix socket path; a non-loopback address needs SOCKS_ADMIN_TOKEN
+    #[arg(long, value_name = "ADDR|PATH")]
+    admin: Option<AdminAddr>,
+
+    /// Export tracing spans: stdout or otlp
+    #[arg(long, value_name = "EXPORTER")]
+    trace: Option<logging::Exporter>,
+
+    /// OTLP gRPC endpoint for --trace otlp [default: OTEL_EXPORTER_OTLP_ENDPOINT or http://localhost:4317]
+    #[arg(long, value_name = "URL")]
+    otlp_endpoint: Option<String>,
+
+    /// Unix socket for handing the listeners over to a new process on upgrade
+    #[arg(long, value_name = "PATH")]
+    handover: Option<PathBuf>,
 }
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 100)]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
-
-    // 設置日誌級別
-    let env = env_logger::Env::default()
-        .filter_or("RUST_LOG", if args.verbose { "debug" } else { "info" });
-    env_logger::Builder::from_env(env).init();
-
-    let addr = format!("{}:{}", args.host, args.port);
-    info!("Starting SOCKS5 server on {}", addr);
-
-    let listener = TcpListener::bind(&addr).await?;
-    info!("SOCKS5 server listening on {}", addr);
-
-    loop {
-        let (socket, addr) = listener.accept().await?;
-        info!("New connection from {}", addr);
-        tokio::spawn(async move {
-            if let Err(e) = process_socks_connection(socket).await {
-                error!("Connection error: {}", e);
+    let cli = Cli::parse();
+    let args = match cli.command {
+        Some(Command::Ctl(ctl)) => {
+            if let Err(e) = ctl::run(ctl).await {
+                eprintln!("error: {:#}", e);
+                std::process::exit(1);
             }
-        });
-    }
+            return Ok(());
+        },
+        Some(Command::Serve(args)) => args,
+        Some(Command::Local(local)) => local.into_args()?,
+        None => cli.serve,
+    };
+    serve(args).await
 }
 
-async fn process_socks_connection(mut socket: TcpStream) -> Result<()> {
-    let mut buf = [0; 1024];
-    let mut is_authenticated: bool = false;
-    // In a loop, read data from the socket and write the data back.
-
-    loop {
-        let n = match socket.read(&mut buf).await {
-            Ok(n) => {
-                if n == 0 {
-                    info!("end the connection.");
-                    return Ok(())
-                }
-                n
-            },
-            Err(e) => {
-                debug!("socket connection disconnect. Reason: {}", e);
-                return Ok(())
-            }
-        };
-
-        let client_ip_info = socket.peer_addr();
-        info!("{:?}", client_ip_info);
-        let buf = &buf[..n];
-        if is_authenticated == false {
-            let mut method_handler = MethodHandler::new(&mut socket, &buf);
-            method_handler.reply().await?;
-            is_authenticated = true;
-        } else {
-            let server_ip_port: SocketAddr = socket.local_addr().unwrap().clone();
-            let client_ip_port: SocketAddr = socket.peer_addr().unwrap().clone();
-            let mut socks_handler = SocksHandler::new(
-                &mut socket,
-                &buf.to_vec(),
-                server_ip_port,
-                client_ip_port,
-            );
-            if let Err(e) = socks_handler.execute_command().await {
-                error!("Socks error: {}", e);
-                return Ok(());
-            }
-        }
+async fn serve(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+
+    // 設置日誌級別
+    logging::init(if args.verbose { "debug" } else { "info" }, args.trace, args.otlp_endpoint.as_deref())?;
+
+    let mut builder = Server::builder()
+        .listen(format!("{}:{}", args.host, args.port))
+        .upstream_udp(args.upstream_udp)
+        .direct_udp(args.direct_udp);
+    for hop in args.upstreams {
+        builder = builder.upstream(hop);
     }
-}
\ No newline at end of file
+    if let Some(path) = args.config {
+        builder = builder.config_file(path);
+    }
+    if let Some(addr) = args.admin {
+        builder = builder.admin(addr);
+    }
+    if let Some(token) = ctl::admin_token() {
+        builder = builder.admin_token(token);
+    }
+    if let Some(path) = args.handover {
+        builder = builder.handover(path);
+    }
+    let server = builder.build().await?;
+    let handle = server.handle();
+    tokio::spawn(async move {
+        match signal().await {
+            Ok(signal) => info!("received {}, shutting down", signal),
+            Err(e) => error!("can not wait for signals: {}", e),
+        }
+        handle.shutdown();
+    });
+    server.run().await?;
+    logging::shutdown();
+    Ok(())
+}
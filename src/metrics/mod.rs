@@ -0,0 +1,201 @@
+use crate::consts;
+use crate::session;
+use crate::socks::SocksCommand;
+use crate::timeouts::Phase;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Upper bounds, in seconds, of the latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 12] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+const COMMANDS: [SocksCommand; 3] = [SocksCommand::TCPConnect, SocksCommand::TCPBind, SocksCommand::UDPAssociate];
+
+// Label for a SOCKS5 authentication method.
+pub fn method_label(method: u8) -> &'static str {
+    match method {
+        consts::SOCKS5_AUTH_METHOD_NONE => "none",
+        consts::SOCKS5_AUTH_METHOD_PASSWORD => "password",
+        _ => "other",
+    }
+}
+
+// The main counters as one object, for `GET /stats`.
+#[derive(Serialize)]
+pub struct Summary {
+    sessions_total: u64,
+    active_sessions: BTreeMap<&'static str, i64>,
+    bytes_up: u64,
+    bytes_down: u64,
+    connects: u64,
+    dns_failures: u64,
+    udp_relayed: u64,
+    udp_dropped: u64,
+    acl_denies: u64,
+    timeouts: BTreeMap<&'static str, u64>,
+}
+
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Histogram {
+            buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS.len()],
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|le| secs <= *le) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (le, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, cumulative);
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+// Process-wide counters, exported in the Prometheus text format.
+pub struct Metrics {
+    timeouts: [AtomicU64; Phase::ALL.len()],
+    // Indexed by the SOCKS command byte.
+    active: [AtomicI64; 4],
+    // (auth method, outcome) -> count
+    handshakes: Mutex<BTreeMap<(&'static str, String), u64>>,
+    pub bytes_up: AtomicU64,
+    pub bytes_down: AtomicU64,
+    pub connect_latency: Histogram,
+    pub dns_latency: Histogram,
+    pub dns_failures: AtomicU64,
+    pub udp_relayed: AtomicU64,
+    pub udp_dropped: AtomicU64,
+    pub acl_denies: AtomicU64,
+}
+
+pub static METRICS: Metrics = Metrics::new();
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            timeouts: [const { AtomicU64::new(0) }; Phase::ALL.len()],
+            active: [const { AtomicI64::new(0) }; 4],
+            handshakes: Mutex::new(BTreeMap::new()),
+            bytes_up: AtomicU64::new(0),
+            bytes_down: AtomicU64::new(0),
+            connect_latency: Histogram::new(),
+            dns_latency: Histogram::new(),
+            dns_failures: AtomicU64::new(0),
+            udp_relayed: AtomicU64::new(0),
+            udp_dropped: AtomicU64::new(0),
+            acl_denies: AtomicU64::new(0),
+        }
+    }
+
+    // Count one timeout and return the new total for that phase.
+    pub fn timeout(&self, phase: Phase) -> u64 {
+        self.timeouts[phase as usize].fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    // Count a session running `command` until the guard is dropped.
+    pub fn session(&self, command: SocksCommand) -> ActiveSession {
+        self.active[command.as_u8() as usize].fetch_add(1, Ordering::Relaxed);
+        ActiveSession { command }
+    }
+
+    // Record how a handshake ended: a SOCKS reply code, or why it stopped earlier.
+    pub fn handshake(&self, method: &'static str, outcome: String) {
+        *self.handshakes.lock().unwrap().entry((method, outcome)).or_default() += 1;
+    }
+
+    pub fn summary(&self) -> Summary {
+        Summary {
+            sessions_total: session::count(),
+            active_sessions: COMMANDS.iter()
+                .map(|command| (command.as_str(), self.active[command.as_u8() as usize].load(Ordering::Relaxed)))
+                .collect(),
+            bytes_up: self.bytes_up.load(Ordering::Relaxed),
+            bytes_down: self.bytes_down.load(Ordering::Relaxed),
+            connects: self.connect_latency.count.load(Ordering::Relaxed),
+            dns_failures: self.dns_failures.load(Ordering::Relaxed),
+            udp_relayed: self.udp_relayed.load(Ordering::Relaxed),
+            udp_dropped: self.udp_dropped.load(Ordering::Relaxed),
+            acl_denies: self.acl_denies.load(Ordering::Relaxed),
+            timeouts: Phase::ALL.iter()
+                .map(|phase| (phase.as_str(), self.timeouts[*phase as usize].load(Ordering::Relaxed)))
+                .collect(),
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counter = |out: &mut String, name: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, value);
+        };
+
+        let _ = writeln!(out, "# HELP socks_active_sessions Sessions currently running a command.");
+        let _ = writeln!(out, "# TYPE socks_active_sessions gauge");
+        for command in COMMANDS {
+            let value = self.active[command.as_u8() as usize].load(Ordering::Relaxed);
+            let _ = writeln!(out, "socks_active_sessions{{command=\"{}\"}} {}", command.as_str(), value);
+        }
+
+        let _ = writeln!(out, "# HELP socks_handshakes_total Handshakes by auth method and reply code or failure.");
+        let _ = writeln!(out, "# TYPE socks_handshakes_total counter");
+        for ((method, outcome), value) in self.handshakes.lock().unwrap().iter() {
+            let _ = writeln!(out, "socks_handshakes_total{{method=\"{}\",reply=\"{}\"}} {}", method, outcome, value);
+        }
+
+        let _ = writeln!(out, "# HELP socks_transferred_bytes_total Bytes relayed, up is client to target.");
+        let _ = writeln!(out, "# TYPE socks_transferred_bytes_total counter");
+        let _ = writeln!(out, "socks_transferred_bytes_total{{direction=\"up\"}} {}", self.bytes_up.load(Ordering::Relaxed));
+        let _ = writeln!(out, "socks_transferred_bytes_total{{direction=\"down\"}} {}", self.bytes_down.load(Ordering::Relaxed));
+
+        self.connect_latency.render(&mut out, "socks_connect_duration_seconds", "Time to establish successful outbound connections.");
+        self.dns_latency.render(&mut out, "socks_dns_duration_seconds", "Time spent resolving names.");
+        counter(&mut out, "socks_dns_failures_total", "Names that could not be resolved.", self.dns_failures.load(Ordering::Relaxed));
+        counter(&mut out, "socks_udp_relayed_datagrams_total", "UDP datagrams relayed in either direction.", self.udp_relayed.load(Ordering::Relaxed));
+        counter(&mut out, "socks_udp_dropped_datagrams_total", "UDP datagrams dropped.", self.udp_dropped.load(Ordering::Relaxed));
+        counter(&mut out, "socks_acl_denies_total", "Requests refused by a block rule.", self.acl_denies.load(Ordering::Relaxed));
+
+        let _ = writeln!(out, "# HELP socks_timeouts_total Timeouts by session phase.");
+        let _ = writeln!(out, "# TYPE socks_timeouts_total counter");
+        for phase in Phase::ALL {
+            let _ = writeln!(out, "socks_timeouts_total{{phase=\"{}\"}} {}", phase, self.timeouts[phase as usize].load(Ordering::Relaxed));
+        }
+        out
+    }
+}
+
+pub struct ActiveSession {
+    command: SocksCommand,
+}
+
+impl Drop for ActiveSession {
+    fn drop(&mut self) {
+        METRICS.active[self.command.as_u8() as usize].fetch_sub(1, Ordering::Relaxed);
+    }
+}
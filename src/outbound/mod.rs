@@ -0,0 +1,157 @@
+use crate::session::Session;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+use tracing::debug;
+use anyhow::{anyhow, Error};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Mode {
+    // Rotate through the addresses, one per connection.
+    #[default]
+    RoundRobin,
+    // Always use the same address for the same user (or client IP when anonymous).
+    Sticky,
+}
+
+impl FromStr for Mode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Mode::RoundRobin),
+            "sticky" => Ok(Mode::Sticky),
+            _ => Err(anyhow!("unknown source mode {:?}", s)),
+        }
+    }
+}
+
+// Which local addresses and interface outbound sockets may use.
+#[derive(Debug, Default)]
+pub struct SourcePolicy {
+    addrs: Vec<IpAddr>,
+    interface: Option<String>,
+    mode: Mode,
+    next: AtomicUsize,
+}
+
+impl SourcePolicy {
+    pub fn new(addrs: Vec<IpAddr>, interface: Option<String>, mode: Mode) -> Result<SourcePolicy, Error> {
+        if interface.is_some() && !cfg!(any(target_os = "android", target_os = "fuchsia", target_os = "linux")) {
+            return Err(anyhow!("binding to an interface is only supported on Linux"));
+        }
+        Ok(SourcePolicy {
+            addrs,
+            interface,
+            mode,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    // Choose the source for one outbound connection, one address per family so
+    // the right one can be used once the destination is resolved.
+    pub fn pick(&self, session: &Session) -> Source {
+        let i = match self.mode {
+            Mode::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed),
+            Mode::Sticky => {
+                let mut hasher = DefaultHasher::new();
+                match &session.user {
+                    Some(user) => user.hash(&mut hasher),
+                    None => session.client_ip_port.ip().hash(&mut hasher),
+                }
+                hasher.finish() as usize
+            },
+        };
+        let nth = |v4: bool| {
+            let family: Vec<IpAddr> = self.addrs.iter().filter(|ip| ip.is_ipv4() == v4).copied().collect();
+            match family.len() {
+                0 => None,
+                n => Some(family[i % n]),
+            }
+        };
+        Source {
+            v4: nth(true),
+            v6: nth(false),
+            interface: self.interface.clone(),
+        }
+    }
+}
+
+// The local addresses and interface picked for one outbound connection.
+#[derive(Debug, Clone, Default)]
+pub struct Source {
+    v4: Option<IpAddr>,
+    v6: Option<IpAddr>,
+    interface: Option<String>,
+}
+
+impl Source {
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    fn bind_device_tcp(&self, socket: &TcpSocket) -> std::io::Result<()> {
+        socket.bind_device(self.interface.as_ref().map(|i| i.as_bytes()))
+    }
+
+    #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+    fn bind_device_tcp(&self, _socket: &TcpSocket) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    fn bind_device_udp(&self, socket: &UdpSocket) -> std::io::Result<()> {
+        socket.bind_device(self.interface.as_ref().map(|i| i.as_bytes()))
+    }
+
+    #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+    fn bind_device_udp(&self, _socket: &UdpSocket) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn addr_for(&self, peer: &SocketAddr) -> Option<IpAddr> {
+        match peer {
+            SocketAddr::V4(_) => self.v4,
+            SocketAddr::V6(_) => self.v6,
+        }
+    }
+
+    pub async fn connect(&self, addr: SocketAddr) -> std::io::Result<TcpStream> {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        if self.interface.is_some() {
+            self.bind_device_tcp(&socket)?;
+        }
+        if let Some(ip) = self.addr_for(&addr) {
+            socket.bind(SocketAddr::new(ip, 0))?;
+            debug!("outbound connection to {} from {}", addr, ip);
+        }
+        socket.connect(addr).await
+    }
+
+    async fn udp_bind_ip(&self, ip: IpAddr) -> std::io::Result<UdpSocket> {
+        let socket = UdpSocket::bind(SocketAddr::new(ip, 0)).await?;
+        if self.interface.is_some() {
+            self.bind_device_udp(&socket)?;
+        }
+        Ok(socket)
+    }
+
+    // Socket that sends datagrams to targets for a UDP association. Prefers the
+    // IPv4 source since the targets are not known yet.
+    pub async fn udp_bind(&self) -> std::io::Result<UdpSocket> {
+        let ip = self.v4.or(self.v6).unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        self.udp_bind_ip(ip).await
+    }
+
+    // Socket for talking to one known peer, such as an upstream UDP relay.
+    pub async fn udp_bind_for(&self, peer: SocketAddr) -> std::io::Result<UdpSocket> {
+        let ip = self.addr_for(&peer).unwrap_or(match peer {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        });
+        self.udp_bind_ip(ip).await
+    }
+}
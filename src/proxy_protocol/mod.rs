@@ -0,0 +1,237 @@
+use ipnet::IpNet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// First 12 bytes of a v2 header.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+// A v1 line is at most 107 bytes including the CRLF.
+const V1_MAX_LEN: usize = 107;
+// TLV types we send: the standard unique ID, carrying the session id, and one
+// from the range reserved for custom use for the authenticated user.
+pub const PP2_TYPE_UNIQUE_ID: u8 = 0x05;
+pub const PP2_TYPE_USERNAME: u8 = 0xE0;
+
+// The [proxy_protocol] section: which listeners sit behind a load balancer
+// and which peers may send a PROXY header on them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    // Listeners that expect the header, every listener when empty.
+    pub listeners: Vec<SocketAddr>,
+    pub trusted: Vec<IpNet>,
+}
+
+impl Settings {
+    // Whether a connection from `peer` on `listener` starts with a header.
+    // Anyone else is taken as a client talking to us directly.
+    pub fn expects(&self, listener: SocketAddr, peer: IpAddr) -> bool {
+        (self.listeners.is_empty() || self.listeners.contains(&listener))
+            && self.trusted.iter().any(|net| net.contains(&peer))
+    }
+}
+
+// Read a v1 or v2 header, and nothing past it, and return the client address
+// it carries. None for health checks (v2 LOCAL, v1 UNKNOWN) and address
+// families other than TCP/UDP over IPv4 or IPv6.
+pub async fn read_header<S: AsyncRead + Unpin>(socket: &mut S) -> std::io::Result<Option<SocketAddr>> {
+    let mut start = [0; 6];
+    socket.read_exact(&mut start).await?;
+    if &start == b"PROXY " {
+        return read_v1(socket).await;
+    }
+    if start[..] == V2_SIGNATURE[..6] {
+        return read_v2(socket).await;
+    }
+    Err(invalid("no PROXY header"))
+}
+
+async fn read_v1<S: AsyncRead + Unpin>(socket: &mut S) -> std::io::Result<Option<SocketAddr>> {
+    let mut line = Vec::new();
+    while !line.ends_with(b"\r\n") {
+        if line.len() + 6 >= V1_MAX_LEN {
+            return Err(invalid("PROXY v1 line too long"));
+        }
+        line.push(socket.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("PROXY v1 line is not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["UNKNOWN", ..] => Ok(None),
+        [family @ ("TCP4" | "TCP6"), src, dst, src_port, dst_port] => {
+            let parse = |ip: &str, port: &str| -> Option<SocketAddr> {
+                let ip: IpAddr = ip.parse().ok()?;
+                if ip.is_ipv4() != (*family == "TCP4") {
+                    return None;
+                }
+                Some(SocketAddr::new(ip, port.parse().ok()?))
+            };
+            match (parse(src, src_port), parse(dst, dst_port)) {
+                (Some(source), Some(_)) => Ok(Some(source)),
+                _ => Err(invalid("bad address in PROXY v1 line")),
+            }
+        },
+        _ => Err(invalid("bad PROXY v1 line")),
+    }
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(socket: &mut S) -> std::io::Result<Option<SocketAddr>> {
+    let mut rest = [0; 10];
+    socket.read_exact(&mut rest).await?;
+    if rest[..6] != V2_SIGNATURE[6..] {
+        return Err(invalid("bad PROXY v2 signature"));
+    }
+    let (version_command, family) = (rest[6], rest[7]);
+    let len = u16::from_be_bytes([rest[8], rest[9]]) as usize;
+    let mut body = vec![0; len];
+    socket.read_exact(&mut body).await?;
+    match version_command {
+        // LOCAL: the balancer's own connection, e.g. a health check.
+        0x20 => return Ok(None),
+        0x21 => {},
+        _ => return Err(invalid("unsupported PROXY v2 version or command")),
+    }
+    // Transport TCP or UDP. Source address, destination address, source port,
+    // destination port; TLVs after the address block are skipped.
+    let port = |i: usize| u16::from_be_bytes([body[i], body[i + 1]]);
+    match family {
+        0x11 | 0x12 if len >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port(8))))
+        },
+        0x21 | 0x22 if len >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&body[..16]).unwrap());
+            Ok(Some(SocketAddr::new(IpAddr::V6(ip), port(32))))
+        },
+        0x11 | 0x12 | 0x21 | 0x22 => Err(invalid("PROXY v2 address block too short")),
+        _ => Ok(None),
+    }
+}
+
+// A v2 PROXY header for a TCP connection from `source` to `destination`.
+// Mixed address families are sent as IPv6, with IPv4 addresses mapped.
+pub fn v2_header(source: SocketAddr, destination: SocketAddr, tlvs: &[(u8, &[u8])]) -> Vec<u8> {
+    let mut addresses = Vec::with_capacity(36);
+    let family = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            addresses.extend_from_slice(&src.octets());
+            addresses.extend_from_slice(&dst.octets());
+            0x11
+        },
+        (src, dst) => {
+            let v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            addresses.extend_from_slice(&v6(src).octets());
+            addresses.extend_from_slice(&v6(dst).octets());
+            0x21
+        },
+    };
+    addresses.extend_from_slice(&source.port().to_be_bytes());
+    addresses.extend_from_slice(&destination.port().to_be_bytes());
+    for (kind, value) in tlvs {
+        addresses.push(*kind);
+        addresses.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        addresses.extend_from_slice(value);
+    }
+    let mut header = V2_SIGNATURE.to_vec();
+    header.extend_from_slice(&[0x21, family]);
+    header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+    header.extend_from_slice(&addresses);
+    header
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(mut bytes: &[u8]) -> (std::io::Result<Option<SocketAddr>>, &[u8]) {
+        let header = read_header(&mut bytes).await;
+        (header, bytes)
+    }
+
+    #[tokio::test]
+    async fn v1_headers() {
+        let (source, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 40000 1080\r\n\x05\x01\x00").await;
+        assert_eq!(source.unwrap(), Some("192.0.2.1:40000".parse().unwrap()));
+        assert_eq!(rest, b"\x05\x01\x00");
+        let (source, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 40000 1080\r\n").await;
+        assert_eq!(source.unwrap(), Some("[2001:db8::1]:40000".parse().unwrap()));
+        let (source, rest) = read(b"PROXY UNKNOWN\r\nGET").await;
+        assert_eq!(source.unwrap(), None);
+        assert_eq!(rest, b"GET");
+        let longest = format!("PROXY TCP6 {0} {0} 65535 65535\r\n", "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff");
+        assert!(read(longest.as_bytes()).await.0.is_ok());
+    }
+
+    #[tokio::test]
+    async fn malformed_v1_headers() {
+        for header in [
+            &b"PROXY TCP4 192.0.2.1 198.51.100.1 40000\r\n"[..],
+            b"PROXY TCP4 2001:db8::1 198.51.100.1 40000 1080\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 70000 1080\r\n",
+            b"PROXY TCP5 192.0.2.1 198.51.100.1 40000 1080\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 40000 1080\n",
+            b"\x05\x01\x00",
+        ] {
+            let (source, _) = read(header).await;
+            assert!(source.is_err(), "{:?}", String::from_utf8_lossy(header));
+        }
+        let endless = [&b"PROXY "[..], &[b'1'; 200]].concat();
+        assert_eq!(read(&endless).await.0.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    fn v2(command: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[command, family]);
+        header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        header.extend_from_slice(body);
+        header
+    }
+
+    #[tokio::test]
+    async fn v2_headers() {
+        let ipv4 = [192, 0, 2, 1, 198, 51, 100, 1, 0x9c, 0x40, 0x04, 0x38];
+        let mut header = v2(0x21, 0x11, &[&ipv4[..], &[0x05, 0x00, 0x01, b'x']].concat());
+        header.extend_from_slice(b"\x05\x01\x00");
+        let (source, rest) = read(&header).await;
+        assert_eq!(source.unwrap(), Some("192.0.2.1:40000".parse().unwrap()));
+        assert_eq!(rest, b"\x05\x01\x00");
+        let ipv6 = [&[0x20, 0x01, 0x0d, 0xb8][..], &[0; 11], &[1], &[0; 16], &[0x9c, 0x40, 0x04, 0x38]].concat();
+        let (source, _) = read(&v2(0x21, 0x21, &ipv6)).await;
+        assert_eq!(source.unwrap(), Some("[2001:db8::1]:40000".parse().unwrap()));
+        // LOCAL, and a unix socket family: no client address.
+        assert_eq!(read(&v2(0x20, 0x00, &[])).await.0.unwrap(), None);
+        assert_eq!(read(&v2(0x21, 0x31, &[0; 216])).await.0.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn malformed_v2_headers() {
+        assert!(read(&v2(0x21, 0x11, &[192, 0, 2, 1])).await.0.is_err());
+        assert!(read(&v2(0x21, 0x21, &[0; 12])).await.0.is_err());
+        assert!(read(&v2(0x11, 0x11, &[0; 12])).await.0.is_err());
+        let mut bad_signature = v2(0x21, 0x11, &[0; 12]);
+        bad_signature[8] = b'X';
+        assert!(read(&bad_signature).await.0.is_err());
+        let truncated = v2(0x21, 0x11, &[0; 12]);
+        assert_eq!(read(&truncated[..20]).await.0.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn written_headers_read_back() {
+        let source: SocketAddr = "192.0.2.1:40000".parse().unwrap();
+        let header = v2_header(source, "198.51.100.1:443".parse().unwrap(), &[(PP2_TYPE_USERNAME, b"alice"), (PP2_TYPE_UNIQUE_ID, b"42")]);
+        assert_eq!(header[12..16], [0x21, 0x11, 0, 12 + 8 + 5]);
+        assert!(header.ends_with(&[0xE0, 0, 5, b'a', b'l', b'i', b'c', b'e', 0x05, 0, 2, b'4', b'2']));
+        let (read_source, rest) = read(&header).await;
+        assert_eq!(read_source.unwrap(), Some(source));
+        assert!(rest.is_empty());
+        // Mixed families go as IPv6, with the IPv4 address mapped.
+        let header = v2_header(source, "[2001:db8::1]:443".parse().unwrap(), &[]);
+        assert_eq!(header[12..16], [0x21, 0x21, 0, 36]);
+        assert_eq!(read(&header).await.0.unwrap(), Some("[::ffff:192.0.2.1]:40000".parse().unwrap()));
+    }
+}
@@ -0,0 +1,228 @@
+use crate::consts;
+use crate::outbound::{Source, SourcePolicy};
+use crate::server::Extensions;
+use crate::session::Session;
+use crate::socks::SocksAddress;
+use crate::socks::handlers::tcp_connect;
+use crate::socks::replies::reply_code_from_io_error;
+use crate::socks::traits::BoxStream;
+use crate::upstream::pool::Pool;
+use crate::upstream::{Chain, UdpRelay, UpstreamError};
+use ipnet::IpNet;
+use std::fmt;
+use std::net::{SocketAddr, IpAddr};
+use std::sync::Arc;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ConnectError {
+    #[error("connection blocked by rule")]
+    Blocked,
+    #[error(transparent)]
+    Upstream(#[from] UpstreamError),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl ConnectError {
+    pub fn reply_code(&self) -> u8 {
+        match self {
+            ConnectError::Blocked => consts::SOCKS5_REPLY_CONNECTION_NOT_ALLOWED,
+            ConnectError::Upstream(e) => e.reply_code(),
+            ConnectError::Io(e) => reply_code_from_io_error(e),
+        }
+    }
+}
+
+// Where the outbound side of a request goes.
+#[derive(Debug, Clone)]
+pub enum Route {
+    Direct,
+    Upstream(String, Arc<Chain>),
+    Pool(Arc<Pool>),
+    Block,
+}
+
+impl Route {
+    // The stream to the target, and the address it resolved to when the
+    // connection is made from here rather than by an upstream.
+    pub async fn connect(&self, dst: &SocksAddress, port: u16, source: &Source, extensions: &Extensions) -> Result<(BoxStream, Option<IpAddr>), ConnectError> {
+        match self {
+            Route::Direct => {
+                let ip = extensions.resolve(dst).await?;
+                let addr = SocketAddr::new(ip, port);
+                let stream: BoxStream = match &extensions.connector {
+                    Some(connector) => connector.connect(addr).await?,
+                    None => Box::new(tcp_connect(addr, source).await?),
+                };
+                Ok((stream, Some(ip)))
+            },
+            Route::Upstream(_, chain) => Ok((chain.connect(dst, port, source).await?, None)),
+            Route::Pool(pool) => Ok((pool.connect(dst, port, source).await?, None)),
+            Route::Block => Err(ConnectError::Blocked),
+        }
+    }
+
+    // Control connection and relay address of the upstream behind this route,
+    // None when datagrams are sent to their targets directly.
+    pub async fn udp_associate(&self, source: &Source) -> Result<Option<(BoxStream, UdpRelay)>, ConnectError> {
+        match self {
+            Route::Direct => Ok(None),
+            Route::Upstream(_, chain) => Ok(Some(chain.udp_associate(source).await?)),
+            Route::Pool(pool) => Ok(Some(pool.udp_associate(source).await?)),
+            Route::Block => Err(ConnectError::Blocked),
+        }
+    }
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Route::Direct => write!(f, "direct"),
+            Route::Upstream(name, _) => write!(f, "upstream:{}", name),
+            Route::Pool(pool) => write!(f, "group:{}", pool.name()),
+            Route::Block => write!(f, "block"),
+        }
+    }
+}
+
+// What to do with a request once a rule (or the default) picked it.
+#[derive(Debug)]
+pub struct Action {
+    pub route: Route,
+    // Overrides the global and per-user source address selection.
+    pub source: Option<SourcePolicy>,
+    // Start TCP streams to the target with a PROXY v2 header.
+    pub proxy_protocol: bool,
+}
+
+impl Action {
+    pub fn new(route: Route) -> Self {
+        Action { route, source: None, proxy_protocol: false }
+    }
+}
+
+// Every non-empty field has to match; inside a field any entry may match.
+#[derive(Debug)]
+pub struct Rule {
+    pub domain_suffix: Vec<String>,
+    pub cidr: Vec<IpNet>,
+    pub port: Vec<u16>,
+    pub user: Vec<String>,
+    pub listener: Vec<SocketAddr>,
+    pub action: Action,
+}
+
+impl Rule {
+    // `resolved` is the address a domain destination resolved to, if it did.
+    fn matches(&self, session: &Session, dst: &SocksAddress, port: u16, resolved: Option<IpAddr>) -> bool {
+        let domain_ok = self.domain_suffix.is_empty() || match dst {
+            SocksAddress::Domain(domain) => {
+                let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+                self.domain_suffix.iter().any(|suffix| {
+                    domain == *suffix || domain.ends_with(&format!(".{}", suffix))
+                })
+            },
+            SocksAddress::IP(_) => false,
+        };
+        // A domain matches through the address it resolved to, so that a name
+        // can not reach a blocked network.
+        let ip = match dst {
+            SocksAddress::IP(ip) => Some(*ip),
+            SocksAddress::Domain(_) => resolved,
+        };
+        let cidr_ok = self.cidr.is_empty() || ip.is_some_and(|ip| {
+            self.cidr.iter().any(|net| net.contains(&canonical(ip)))
+        });
+        let port_ok = self.port.is_empty() || self.port.contains(&port);
+        let user_ok = self.user.is_empty() || session.user.as_ref().is_some_and(|u| self.user.contains(u));
+        let listener_ok = self.listener.is_empty() || self.listener.contains(&session.listener);
+        domain_ok && cidr_ok && port_ok && user_ok && listener_ok
+    }
+}
+
+// IPv4-mapped IPv6 addresses should match IPv4 networks.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+// Ordered rule table, first match wins.
+#[derive(Debug)]
+pub struct Router {
+    rules: Vec<Rule>,
+    default: Action,
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Router::new(Vec::new(), Route::Direct)
+    }
+}
+
+impl Router {
+    pub fn new(rules: Vec<Rule>, default: Route) -> Self {
+        Router { rules, default: Action::new(default) }
+    }
+
+    // Whether `dst` has to be resolved before routing: it is a domain and some
+    // rule matches on CIDR.
+    pub fn resolves(&self, dst: &SocksAddress) -> bool {
+        matches!(dst, SocksAddress::Domain(_)) && self.rules.iter().any(|rule| !rule.cidr.is_empty())
+    }
+
+    pub fn route(&self, session: &Session, dst: &SocksAddress, port: u16, resolved: Option<IpAddr>) -> &Action {
+        self.rules.iter()
+            .find(|rule| rule.matches(session, dst, port, resolved))
+            .map(|rule| &rule.action)
+            .unwrap_or(&self.default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn block(cidr: &str) -> Rule {
+        Rule {
+            domain_suffix: Vec::new(),
+            cidr: vec![cidr.parse().unwrap()],
+            port: Vec::new(),
+            user: Vec::new(),
+            listener: Vec::new(),
+            action: Action::new(Route::Block),
+        }
+    }
+
+    fn session() -> Session {
+        let addr: SocketAddr = "127.0.0.1:1080".parse().unwrap();
+        Session::new(addr, addr, "127.0.0.1:40000".parse().unwrap(), &Config::default())
+    }
+
+    #[test]
+    fn cidr_blocks_resolved_host_names() {
+        let router = Router::new(vec![block("127.0.0.0/8")], Route::Direct);
+        let session = session();
+        let localhost = SocksAddress::Domain("localhost".to_string());
+        assert!(router.resolves(&localhost));
+        let loopback = Some("127.0.0.1".parse().unwrap());
+        assert!(matches!(router.route(&session, &localhost, 80, loopback).route, Route::Block));
+        assert!(matches!(router.route(&session, &SocksAddress::IP("::ffff:127.0.0.1".parse().unwrap()), 80, None).route, Route::Block));
+        let elsewhere = Some("192.0.2.1".parse().unwrap());
+        assert!(matches!(router.route(&session, &localhost, 80, elsewhere).route, Route::Direct));
+    }
+
+    #[test]
+    fn resolves_only_for_cidr_rules() {
+        let session = session();
+        let mut rule = block("10.0.0.0/8");
+        rule.cidr.clear();
+        rule.domain_suffix.push("example.com".to_string());
+        let router = Router::new(vec![rule], Route::Direct);
+        assert!(!router.resolves(&SocksAddress::Domain("example.com".to_string())));
+        assert!(matches!(router.route(&session, &SocksAddress::Domain("www.example.com".to_string()), 443, None).route, Route::Block));
+    }
+}
@@ -0,0 +1,146 @@
+use crate::socks::SocksAddress;
+use crate::socks::traits::BoxStream;
+use async_trait::async_trait;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Checks username/password logins, after the `[users]` of the config file.
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    /// Whether the login is accepted; false lets the next authenticator try.
+    async fn authenticate(&self, username: &str, password: &str) -> bool;
+}
+
+/// Resolves the domain names of direct connections and UDP datagrams.
+#[async_trait]
+pub trait Resolver: Send + Sync {
+    /// The address to connect or send to; an error is answered like a
+    /// failed DNS lookup.
+    async fn resolve(&self, host: &str) -> std::io::Result<IpAddr>;
+}
+
+/// Opens direct connections to targets, in place of a TCP connection from the
+/// configured source address. Upstreams are still dialed over TCP.
+#[async_trait]
+pub trait Connector: Send + Sync {
+    /// A stream to `addr`, the resolved target; its error picks the reply code.
+    async fn connect(&self, addr: SocketAddr) -> std::io::Result<BoxStream>;
+}
+
+/// A request about to be routed.
+#[derive(Debug, Clone)]
+pub struct Request {
+    /// The client, or the address a PROXY header gave for it.
+    pub client: SocketAddr,
+    /// The listener the client connected to.
+    pub listener: SocketAddr,
+    /// The authenticated user, if any.
+    pub user: Option<String>,
+    /// `connect`, `udp_associate` or `forward`.
+    pub command: &'static str,
+    /// The destination as the client gave it: an IP address or a domain name.
+    pub host: String,
+    pub port: u16,
+}
+
+/// Decides before the routing rules whether a request may go out at all; a
+/// denied request is answered like a `block` route. It sees every destination
+/// the server sends to: the target of each CONNECT, HTTP CONNECT and HTTP
+/// forward request, and the destination of each UDP datagram, as a request
+/// with the `udp_associate` command, a denied datagram being dropped. The UDP
+/// ASSOCIATE request itself is asked about too, with the address the client
+/// gave, usually 0.0.0.0:0. A forwarded HTTP connection carries one request.
+pub trait Acl: Send + Sync {
+    /// Called on the session's task for every datagram too, so it should be cheap.
+    fn allow(&self, request: &Request) -> bool;
+}
+
+/// A finished session, with what the access log records. The fields a
+/// session did not get to, e.g. the destination of one that failed to
+/// authenticate, are None.
+#[derive(Debug, Clone)]
+pub struct Closed {
+    /// The session id, as in the admin API and the logs.
+    pub id: u64,
+    pub client: SocketAddr,
+    pub user: Option<String>,
+    /// `connect`, `bind`, `udp_associate`, `forward` or `mux`.
+    pub command: Option<&'static str>,
+    /// `host:port` as the client asked for it.
+    pub destination: Option<String>,
+    /// `direct`, `block`, `upstream:<name>` or `group:<name>`.
+    pub route: Option<String>,
+    /// The SOCKS5 reply code sent to the client.
+    pub reply: Option<u8>,
+    /// Bytes from the client to the target and back.
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub duration: Duration,
+    /// Why the session ended, one of the access log's `close` values.
+    pub close: &'static str,
+}
+
+/// Called as connections come and go. Hooks run on the connection's task and
+/// should not block.
+pub trait Hooks: Send + Sync {
+    /// A client connected to `listener`; false closes the connection before the handshake.
+    fn on_connect(&self, _client: SocketAddr, _listener: SocketAddr) -> bool {
+        true
+    }
+
+    /// A session ended, with its access log fields.
+    fn on_close(&self, _session: &Closed) {}
+}
+
+// What an embedding program plugged into the server. Kept across config reloads.
+#[derive(Clone, Default)]
+pub struct Extensions {
+    pub authenticators: Vec<Arc<dyn Authenticator>>,
+    pub resolver: Option<Arc<dyn Resolver>>,
+    pub connector: Option<Arc<dyn Connector>>,
+    pub acl: Option<Arc<dyn Acl>>,
+    pub hooks: Vec<Arc<dyn Hooks>>,
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("authenticators", &self.authenticators.len())
+            .field("resolver", &self.resolver.is_some())
+            .field("connector", &self.connector.is_some())
+            .field("acl", &self.acl.is_some())
+            .field("hooks", &self.hooks.len())
+            .finish()
+    }
+}
+
+impl Extensions {
+    pub async fn authenticate(&self, username: &str, password: &str) -> bool {
+        for authenticator in &self.authenticators {
+            if authenticator.authenticate(username, password).await {
+                return true;
+            }
+        }
+        false
+    }
+
+    pub async fn resolve(&self, dst: &SocksAddress) -> std::io::Result<IpAddr> {
+        match (&self.resolver, dst) {
+            (Some(resolver), SocksAddress::Domain(host)) => resolver.resolve(host).await,
+            _ => dst.get_ip_addr().await,
+        }
+    }
+
+    pub fn allow(&self, request: &Request) -> bool {
+        match &self.acl {
+            Some(acl) => acl.allow(request),
+            None => true,
+        }
+    }
+
+    pub fn on_connect(&self, client: SocketAddr, listener: SocketAddr) -> bool {
+        self.hooks.iter().all(|hooks| hooks.on_connect(client, listener))
+    }
+}
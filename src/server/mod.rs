@@ -0,0 +1,730 @@
+use crate::admin::{self, AdminAddr};
+use crate::config::{Config, Shared, UpstreamUdp};
+use crate::consts;
+use crate::http;
+use crate::proxy_protocol;
+use crate::session::Session;
+use crate::shutdown::Shutdown;
+use crate::socks::handlers::{reply_malformed, AuthHandler, SocksHandler, MethodHandler};
+use crate::timeouts::Phase;
+use crate::tls;
+use crate::transport::Transport;
+use crate::tunnel;
+use crate::upstream::{Chain, Hop};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
+use anyhow::{anyhow, Result};
+
+mod extensions;
+
+pub use extensions::{Acl, Authenticator, Closed, Connector, Extensions, Hooks, Request, Resolver};
+
+/// Everything a server is started with; the config file, when there is one,
+/// adds its own listeners, users and rules.
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// let server = socks::Server::builder()
+///     .listen("127.0.0.1:0")
+///     .build()
+///     .await?;
+/// let addr = server.local_addrs()[0];
+/// let handle = server.handle();
+/// tokio::spawn(server.run());
+/// // ... use the proxy on `addr` ...
+/// handle.shutdown();
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct Builder {
+    listen: Vec<String>,
+    listeners: Vec<TcpListener>,
+    config: Option<PathBuf>,
+    upstreams: Vec<Hop>,
+    upstream_udp: bool,
+    direct_udp: bool,
+    admin: Option<AdminAddr>,
+    admin_token: Option<String>,
+    handover: Option<PathBuf>,
+    extensions: Extensions,
+}
+
+impl Builder {
+    /// Serve SOCKS on `addr`, `host:port`; port 0 picks a free one.
+    pub fn listen(mut self, addr: impl Into<String>) -> Builder {
+        self.listen.push(addr.into());
+        self
+    }
+
+    /// Serve SOCKS on a listener bound by the caller.
+    pub fn listener(mut self, listener: TcpListener) -> Builder {
+        self.listeners.push(listener);
+        self
+    }
+
+    /// TOML file with users, upstreams, rules and the other settings, as for
+    /// `--config`. It is re-read on `POST /reload`.
+    pub fn config_file(mut self, path: impl Into<PathBuf>) -> Builder {
+        self.config = Some(path.into());
+        self
+    }
+
+    /// Parent proxy chain for requests no rule routes elsewhere, in order.
+    pub fn upstream(mut self, hop: Hop) -> Builder {
+        self.upstreams.push(hop);
+        self
+    }
+
+    /// Relay UDP ASSOCIATE through the last upstream too, as `--upstream-udp`.
+    pub fn upstream_udp(mut self, enabled: bool) -> Builder {
+        self.upstream_udp = enabled;
+        self
+    }
+
+    /// Without `upstream_udp`, serve UDP ASSOCIATE routed to an upstream
+    /// directly instead of refusing it, as `--direct-udp`.
+    pub fn direct_udp(mut self, enabled: bool) -> Builder {
+        self.direct_udp = enabled;
+        self
+    }
+
+    /// Serve the admin API on a TCP address or a Unix socket, as `--admin`.
+    /// Without `admin_token` the address has to be a loopback one.
+    pub fn admin(mut self, addr: AdminAddr) -> Builder {
+        self.admin = Some(addr);
+        self
+    }
+
+    /// Bearer token every admin API request has to carry.
+    pub fn admin_token(mut self, token: impl Into<String>) -> Builder {
+        self.admin_token = Some(token.into());
+        self
+    }
+
+    /// Unix socket to take the listeners over from a running process and hand them on.
+    pub fn handover(mut self, path: impl Into<PathBuf>) -> Builder {
+        self.handover = Some(path.into());
+        self
+    }
+
+    /// Checks logins the config file's users did not match; several are
+    /// tried in order. Any authenticator makes a password required.
+    pub fn authenticator(mut self, authenticator: impl Authenticator + 'static) -> Builder {
+        self.extensions.authenticators.push(Arc::new(authenticator));
+        self
+    }
+
+    /// Resolves the domain names of direct connections and UDP datagrams,
+    /// in place of the system resolver.
+    pub fn resolver(mut self, resolver: impl Resolver + 'static) -> Builder {
+        self.extensions.resolver = Some(Arc::new(resolver));
+        self
+    }
+
+    /// Opens direct connections to targets, in place of TCP.
+    pub fn connector(mut self, connector: impl Connector + 'static) -> Builder {
+        self.extensions.connector = Some(Arc::new(connector));
+        self
+    }
+
+    /// Allows or denies every destination before the routing rules.
+    pub fn acl(mut self, acl: impl Acl + 'static) -> Builder {
+        self.extensions.acl = Some(Arc::new(acl));
+        self
+    }
+
+    /// Called as connections come and go; several may be added.
+    pub fn hooks(mut self, hooks: impl Hooks + 'static) -> Builder {
+        self.extensions.hooks.push(Arc::new(hooks));
+        self
+    }
+
+    /// Load the config and bind every listener, the admin one included, so a
+    /// bad address fails here.
+    pub async fn build(self) -> Result<Server> {
+        let upstream_udp = match (self.upstream_udp, self.direct_udp) {
+            (true, _) => UpstreamUdp::Forward,
+            (false, true) => UpstreamUdp::Direct,
+            (false, false) => UpstreamUdp::Refuse,
+        };
+        let shared = Arc::new(Shared::load(self.config, Chain::new(self.upstreams), upstream_udp, Arc::new(self.extensions))?);
+        let config = shared.get();
+        for addr in &self.listen {
+            info!("Starting SOCKS5 server on {}", addr);
+        }
+
+        let mut inherited = Vec::new();
+        if let Some(path) = &self.handover {
+            inherited = take_listeners(path).await?;
+        }
+        let mut listeners: Vec<_> = self.listeners.into_iter().map(|l| (l, Transport::Plain)).collect();
+        for addr in &self.listen {
+            listeners.push((bind(addr.as_str(), &mut inherited).await?, Transport::Plain));
+        }
+        for addr in &config.listen {
+            listeners.push((bind(addr, &mut inherited).await?, Transport::Plain));
+        }
+        let acceptor = config.tls.as_ref().map(tls::Acceptor::new).transpose()?;
+        if let (Some(settings), Some(acceptor)) = (&config.tls, &acceptor) {
+            for addr in &settings.listen {
+                listeners.push((bind(addr, &mut inherited).await?, Transport::Tls(acceptor.clone())));
+            }
+        }
+        if let (Some(settings), Some(acceptor)) = (&config.tunnel, &acceptor) {
+            for addr in &settings.listen {
+                listeners.push((bind(addr, &mut inherited).await?, Transport::Tunnel(acceptor.clone())));
+            }
+        }
+        if let Some(settings) = &config.websocket {
+            let transport = Transport::WebSocket {
+                path: settings.path.as_str().into(),
+                tls: if settings.tls { acceptor.clone() } else { None },
+            };
+            for addr in &settings.listen {
+                listeners.push((bind(addr, &mut inherited).await?, transport.clone()));
+            }
+        }
+        if listeners.is_empty() {
+            return Err(anyhow!("no listeners configured"));
+        }
+        // QUIC endpoints are UDP and are not handed over; after a handover the
+        // old process may still hold them for its open connections.
+        let mut quic_endpoints = Vec::new();
+        if let (Some(settings), Some(acceptor)) = (&config.tunnel, &acceptor) {
+            for addr in &settings.quic {
+                match tunnel::quic::endpoint(*addr, acceptor) {
+                    Ok(endpoint) => quic_endpoints.push((*addr, Some(endpoint), acceptor.clone())),
+                    Err(e) if self.handover.is_some() => {
+                        warn!("can not bind {} for QUIC yet, retrying: {}", addr, e);
+                        quic_endpoints.push((*addr, None, acceptor.clone()));
+                    },
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        let admin = match &self.admin {
+            Some(addr) => Some((admin::bind(addr, self.admin_token.as_deref()).await?, self.admin_token)),
+            None => None,
+        };
+        Ok(Server {
+            shared,
+            listeners,
+            quic_endpoints,
+            admin,
+            handover: self.handover,
+            stop: Arc::new(watch::Sender::new(false)),
+        })
+    }
+}
+
+/// A server with its listeners bound, serving once `run` is polled.
+///
+/// Metrics, the live sessions and the log level are process-wide: a second
+/// server in the same process counts into the same metrics, its sessions
+/// show up in the first one's admin API and `POST /log-level` changes both.
+/// Run one server per process when they have to be told apart.
+pub struct Server {
+    shared: Arc<Shared>,
+    listeners: Vec<(TcpListener, Transport)>,
+    quic_endpoints: Vec<(SocketAddr, Option<quinn::Endpoint>, tls::Acceptor)>,
+    admin: Option<(admin::Listener, Option<String>)>,
+    handover: Option<PathBuf>,
+    stop: Arc<watch::Sender<bool>>,
+}
+
+/// Stops a running server from elsewhere, like a signal does for the binary.
+#[derive(Clone)]
+pub struct Handle {
+    stop: Arc<watch::Sender<bool>>,
+}
+
+impl Handle {
+    /// Stop accepting and let `run` drain the live sessions, then return.
+    pub fn shutdown(&self) {
+        self.stop.send_replace(true);
+    }
+}
+
+impl Server {
+    /// A builder with no listeners, no config file and no extensions.
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+
+    /// Addresses of the TCP listeners, those given to the builder first.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners.iter().filter_map(|(l, _)| l.local_addr().ok()).collect()
+    }
+
+    /// Addresses of the QUIC tunnel endpoints bound so far.
+    pub fn quic_addrs(&self) -> Vec<SocketAddr> {
+        self.quic_endpoints.iter().filter_map(|(_, endpoint, _)| endpoint.as_ref()?.local_addr().ok()).collect()
+    }
+
+    /// A handle to shut the server down once `run` owns it.
+    pub fn handle(&self) -> Handle {
+        Handle { stop: self.stop.clone() }
+    }
+
+    /// Accept until shut down or the listeners were handed over, then drain
+    /// the live sessions for up to the `drain` timeout and return. Fails when
+    /// a listener or the handover socket does, after draining all the same.
+    pub async fn run(self) -> Result<()> {
+        let Server { shared, listeners, quic_endpoints, admin, handover, stop } = self;
+        let handover = serve_handover(handover, &listeners.iter().map(|(l, _)| l).collect::<Vec<_>>());
+        let shutdown = Shutdown::new();
+        let mut accept_loops = tokio::task::JoinSet::new();
+        for (listener, transport) in listeners {
+            match &transport {
+                Transport::Plain => info!("SOCKS5 server listening on {}", listener.local_addr()?),
+                Transport::Tunnel(_) => info!("tunnel server listening on {}", listener.local_addr()?),
+                transport => info!("SOCKS5 over {} server listening on {}", transport.name(), listener.local_addr()?),
+            }
+            accept_loops.spawn(accept_loop(listener, transport, shared.clone(), shutdown.clone()));
+        }
+        for (addr, endpoint, acceptor) in quic_endpoints {
+            accept_loops.spawn(quic_accept_loop(addr, endpoint, acceptor, shared.clone(), shutdown.clone()));
+        }
+        // The admin API stays up while draining and is closed before `run`
+        // returns, or when it is dropped.
+        let mut admin_task = tokio::task::JoinSet::new();
+        if let Some((listener, token)) = admin {
+            let shared = shared.clone();
+            admin_task.spawn(async move {
+                if let Err(e) = admin::serve(listener, token, shared).await {
+                    error!("admin listener failed: {}", e);
+                }
+            });
+        }
+        let mut stopped = stop.subscribe();
+        let res = tokio::select! {
+            res = accept_loops.join_next() => match res {
+                Some(res) => res.map_err(anyhow::Error::from).and_then(|res| res),
+                None => Ok(()),
+            },
+            _ = stopped.wait_for(|stop| *stop) => Ok(()),
+            res = handover => res,
+        };
+        // Live sessions get the drain timeout whatever stopped the server,
+        // the error is only returned after.
+        if let Err(e) = &res {
+            error!("stopping: {:#}", e);
+        }
+        shutdown.drain(shared.get().timeouts.drain).await;
+        admin_task.shutdown().await;
+        // Write out the access log records of the drained sessions, joining
+        // the writer off the runtime.
+        if let Some(writer) = shared.get().access_log.as_ref().and_then(|log| log.close()) {
+            let _ = tokio::task::spawn_blocking(move || writer.join()).await;
+        }
+        res
+    }
+}
+
+// Reuse a listener taken over from the previous process when it is bound to
+// the same address, otherwise bind a new one.
+async fn bind<A: tokio::net::ToSocketAddrs>(addr: A, inherited: &mut Vec<TcpListener>) -> Result<TcpListener> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host(addr).await?.collect();
+    for addr in &addrs {
+        if let Some(i) = inherited.iter().position(|l| l.local_addr().ok() == Some(*addr)) {
+            return Ok(inherited.swap_remove(i));
+        }
+    }
+    Ok(TcpListener::bind(&addrs[..]).await?)
+}
+
+#[cfg(unix)]
+async fn take_listeners(path: &Path) -> Result<Vec<TcpListener>> {
+    crate::handover::take(path).await
+}
+
+#[cfg(not(unix))]
+async fn take_listeners(_path: &Path) -> Result<Vec<TcpListener>> {
+    Err(anyhow::anyhow!("--handover is only supported on Unix"))
+}
+
+// Resolves once the listeners were handed to a new process, never when
+// handover is off.
+#[cfg(unix)]
+fn serve_handover(path: Option<PathBuf>, listeners: &[&TcpListener]) -> impl std::future::Future<Output = Result<()>> {
+    use std::os::fd::AsRawFd;
+    let fds: Vec<_> = listeners.iter().map(|l| l.as_raw_fd()).collect();
+    async move {
+        match path {
+            Some(path) => crate::handover::serve(&path, fds).await,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn serve_handover(_path: Option<PathBuf>, _listeners: &[&TcpListener]) -> impl std::future::Future<Output = Result<()>> {
+    std::future::pending()
+}
+
+// Pause after a failed accept, so that running out of descriptors does not
+// spin the loop.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+async fn accept_loop(listener: TcpListener, transport: Transport, shared: Arc<Shared>, shutdown: Arc<Shutdown>) -> Result<()> {
+    let listener_addr = listener.local_addr()?;
+    loop {
+        let (socket, addr) = tokio::select! {
+            res = listener.accept() => match res {
+                Ok(accepted) => accepted,
+                // Out of file descriptors, or a connection aborted before it
+                // was accepted: the listener is fine, try again shortly.
+                Err(e) => {
+                    warn!("accept on {} failed: {}", listener_addr, e);
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                },
+            },
+            _ = shutdown.stopped() => {
+                info!("no longer accepting on {}", listener_addr);
+                return Ok(());
+            },
+        };
+        info!("New connection from {}", addr);
+        let config = shared.get();
+        if !config.extensions.on_connect(addr, listener_addr) {
+            info!("connection from {} refused by a hook", addr);
+            continue;
+        }
+        let shutdown = shutdown.clone();
+        let guard = shutdown.enter();
+        // Root span of the session, the phases below are its children.
+        let span = info_span!(
+            "session",
+            session.id = tracing::field::Empty,
+            client = tracing::field::Empty,
+            user = tracing::field::Empty,
+            destination = tracing::field::Empty,
+            route = tracing::field::Empty,
+        );
+        let transport = transport.clone();
+        tokio::spawn(async move {
+            let _guard = guard;
+            tokio::select! {
+                res = process_connection(socket, transport, listener_addr, config) => {
+                    if let Err(e) = res {
+                        error!("Connection error: {}", e);
+                    }
+                },
+                _ = shutdown.forced() => {
+                    info!("force closing connection from {}", addr);
+                },
+            }
+        }.instrument(span));
+    }
+}
+
+// Streams of QUIC tunnel connections, each served like a tunnel connection.
+async fn quic_accept_loop(addr: SocketAddr, endpoint: Option<quinn::Endpoint>, acceptor: tls::Acceptor, shared: Arc<Shared>, shutdown: Arc<Shutdown>) -> Result<()> {
+    let endpoint = match endpoint {
+        Some(endpoint) => endpoint,
+        None => loop {
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs(1)) => {},
+                _ = shutdown.stopped() => return Ok(()),
+            }
+            if let Ok(endpoint) = tunnel::quic::endpoint(addr, &acceptor) {
+                break endpoint;
+            }
+        },
+    };
+    let listener_addr = endpoint.local_addr()?;
+    info!("tunnel server listening on {} (QUIC)", listener_addr);
+    loop {
+        let incoming = tokio::select! {
+            incoming = endpoint.accept() => match incoming {
+                Some(incoming) => incoming,
+                None => return Ok(()),
+            },
+            _ = shutdown.stopped() => {
+                info!("no longer accepting on {}", listener_addr);
+                return Ok(());
+            },
+        };
+        let addr = incoming.remote_address();
+        info!("New QUIC connection from {}", addr);
+        if !shared.get().extensions.on_connect(addr, listener_addr) {
+            info!("connection from {} refused by a hook", addr);
+            incoming.refuse();
+            continue;
+        }
+        let (shared, acceptor, shutdown) = (shared.clone(), acceptor.clone(), shutdown.clone());
+        let guard = shutdown.enter();
+        tokio::spawn(async move {
+            let _guard = guard;
+            tokio::select! {
+                res = process_quic_connection(incoming, acceptor, listener_addr, shared, &shutdown) => {
+                    if let Err(e) = res {
+                        error!("Connection error: {}", e);
+                    }
+                },
+                _ = shutdown.forced() => {
+                    info!("force closing connection from {}", addr);
+                },
+            }
+        });
+    }
+}
+
+async fn process_quic_connection(incoming: quinn::Incoming, acceptor: tls::Acceptor, listener: SocketAddr, shared: Arc<Shared>, shutdown: &Shutdown) -> Result<()> {
+    let client = incoming.remote_address();
+    let handshake = tunnel::quic::Connection::accept(incoming, &acceptor).instrument(info_span!("tls", client = %client));
+    let (connection, peer) = match shared.get().timeouts.run(Phase::Greeting, handshake).await {
+        Ok(Ok(accepted)) => accepted,
+        Ok(Err(e)) => {
+            debug!("QUIC handshake with {} failed: {}", client, e);
+            return Ok(());
+        },
+        Err(_) => {
+            debug!("QUIC handshake with {} timed out", client);
+            return Ok(());
+        },
+    };
+    let server = connection.server(listener);
+    // Dropping the connection task stops its streams too.
+    let mut streams = tokio::task::JoinSet::new();
+    loop {
+        tokio::select! {
+            res = connection.accept_stream() => {
+                let (stream, datagrams) = match res {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        debug!("QUIC connection from {} closed: {}", client, e);
+                        break;
+                    },
+                };
+                let span = info_span!(
+                    "session",
+                    session.id = tracing::field::Empty,
+                    client = %client,
+                    user = tracing::field::Empty,
+                    destination = tracing::field::Empty,
+                    route = tracing::field::Empty,
+                );
+                let protocol = Protocol::Tunnel(Some(datagrams));
+                let serve = process_socks_connection(stream, peer.clone(), protocol, listener, server, client, shared.get());
+                streams.spawn(async move {
+                    if let Err(e) = serve.await {
+                        error!("Connection error: {}", e);
+                    }
+                }.instrument(span));
+            },
+            Some(_) = streams.join_next(), if !streams.is_empty() => {},
+            // No new streams once draining; the connection closes after the open ones.
+            _ = shutdown.stopped() => break,
+        }
+    }
+    while streams.join_next().await.is_some() {}
+    Ok(())
+}
+
+// What runs over a connection once its transport is up.
+enum Protocol {
+    Socks,
+    // From a `socks local` instance, with the datagrams of UDP associations
+    // when the transport has a channel for them.
+    Tunnel(Option<tunnel::Datagrams>),
+}
+
+enum Stage {
+    Method,
+    Auth,
+    Request,
+}
+
+async fn process_connection(mut socket: TcpStream, transport: Transport, listener: SocketAddr, config: Arc<Config>) -> Result<()> {
+    let (server, mut client) = (socket.local_addr()?, socket.peer_addr()?);
+    // Behind a load balancer the client address comes from the PROXY header.
+    if config.proxy_protocol.as_ref().is_some_and(|p| p.expects(listener, client.ip())) {
+        let header = proxy_protocol::read_header(&mut socket).instrument(info_span!("proxy_protocol"));
+        match config.timeouts.run(Phase::Greeting, header).await {
+            Ok(Ok(source)) => if let Some(source) = source {
+                debug!("{} forwards {}", client, source);
+                client = source;
+            },
+            Ok(Err(e)) => {
+                info!("bad PROXY header from {}: {}", client, e);
+                return Ok(());
+            },
+            Err(_) => {
+                debug!("PROXY header from {} timed out", client);
+                return Ok(());
+            },
+        }
+    }
+    Span::current().record("client", tracing::field::display(client));
+    if let Transport::Plain = transport {
+        return process_socks_connection(socket, None, Protocol::Socks, listener, server, client, config).await;
+    }
+    // TLS and WebSocket handshakes count against the greeting timeout.
+    let protocol = match transport {
+        Transport::Tunnel(_) => Protocol::Tunnel(None),
+        _ => Protocol::Socks,
+    };
+    match config.timeouts.run(Phase::Greeting, transport.accept(socket)).await {
+        Ok(Ok((stream, peer))) => process_socks_connection(stream, peer, protocol, listener, server, client, config).await,
+        Ok(Err(e)) => {
+            debug!("{} handshake with {} failed: {}", transport.name(), client, e);
+            Ok(())
+        },
+        Err(_) => {
+            debug!("{} handshake with {} timed out", transport.name(), client);
+            Ok(())
+        },
+    }
+}
+
+// Serve a client connection, or a `socks local` instance.
+async fn process_socks_connection<S>(
+    mut socket: S,
+    peer: Option<tls::Peer>,
+    protocol: Protocol,
+    listener: SocketAddr,
+    server: SocketAddr,
+    client: SocketAddr,
+    config: Arc<Config>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut session = Session::new(listener, server, client, &config);
+    if let Some(peer) = peer {
+        debug!("client certificate of {} names {:?}", client, peer.identity);
+        // A certificate that is enough on its own authenticates the user.
+        if !peer.password_required {
+            Span::current().record("user", peer.identity.as_str());
+            session.set_user(peer.identity.clone());
+        }
+        session.certificate = Some(peer.identity);
+    }
+    let stats = session.stats.clone();
+    Span::current().record("session.id", stats.id);
+    let serve = async {
+        match protocol {
+            Protocol::Tunnel(datagrams) => tunnel::serve(&mut socket, &mut session, config, datagrams).await,
+            Protocol::Socks => handshake(&mut socket, &mut session, config).await,
+        }
+    };
+    tokio::select! {
+        res = serve => res,
+        _ = stats.killed() => {
+            info!("session {} killed", stats.id);
+            stats.close("killed");
+            Ok(())
+        },
+    }
+}
+
+async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(socket: &mut S, session: &mut Session, config: Arc<Config>) -> Result<()> {
+    let mut buf = [0; 1024];
+    let mut stage = Stage::Method;
+    // In a loop, read data from the socket and write the data back.
+
+    loop {
+        let phase = match stage {
+            Stage::Method => Phase::Greeting,
+            Stage::Auth => Phase::Auth,
+            Stage::Request => Phase::Request,
+        };
+        let span = match stage {
+            Stage::Method => info_span!("method"),
+            Stage::Auth => info_span!("auth"),
+            Stage::Request => info_span!("request"),
+        };
+        let n = match config.timeouts.run(phase, socket.read(&mut buf)).instrument(span.clone()).await {
+            Err(_) => {
+                debug!("{} timed out in {} phase", session.client_ip_port, phase);
+                session.stats.close(match phase {
+                    Phase::Greeting => "greeting_timeout",
+                    Phase::Auth => "auth_timeout",
+                    _ => "request_timeout",
+                });
+                return Ok(())
+            },
+            Ok(Ok(n)) => {
+                if n == 0 {
+                    info!("end the connection.");
+                    session.stats.close("client_closed");
+                    return Ok(())
+                }
+                n
+            },
+            Ok(Err(e)) => {
+                debug!("socket connection disconnect. Reason: {}", e);
+                session.stats.close("client_error");
+                return Ok(())
+            }
+        };
+
+        let buf = &buf[..n];
+        match stage {
+            // SOCKS4 has no method negotiation, the first message is the request.
+            Stage::Method if buf[0] == consts::SOCKS4_VERSION => {
+                return request(socket, buf, session, &config, info_span!("request")).await;
+            },
+            // So is an HTTP proxy request, served on the same port.
+            Stage::Method if http::server::is_http(buf[0]) => {
+                if let Err(e) = http::server::serve(&mut *socket, buf, session, config.clone()).await {
+                    error!("HTTP proxy error: {}", e);
+                }
+                return Ok(());
+            },
+            Stage::Method => {
+                let mut method_handler = MethodHandler::new(&mut *socket, buf);
+                stage = match method_handler.reply(session.user.is_none() && config.password_required()).instrument(span).await? {
+                    consts::SOCKS5_AUTH_METHOD_PASSWORD => Stage::Auth,
+                    consts::SOCKS5_AUTH_METHOD_NONE => Stage::Request,
+                    _ => {
+                        info!("no acceptable method from {}", session.client_ip_port);
+                        session.stats.close("no_method");
+                        return Ok(());
+                    },
+                };
+            },
+            Stage::Auth => {
+                let mut auth_handler = AuthHandler::new(&mut *socket, buf);
+                match auth_handler.reply(&config).instrument(span).await? {
+                    Some(user) => {
+                        Span::current().record("user", user.as_str());
+                        session.set_user(user);
+                    },
+                    None => {
+                        session.stats.close("auth_failed");
+                        return Ok(());
+                    },
+                }
+                stage = Stage::Request;
+            },
+            Stage::Request => return request(socket, buf, session, &config, span).await,
+        }
+    }
+}
+
+async fn request<S: AsyncRead + AsyncWrite + Unpin>(socket: &mut S, buf: &[u8], session: &Session, config: &Arc<Config>, span: Span) -> Result<()> {
+    let handler = span.in_scope(|| SocksHandler::new(
+        &mut *socket,
+        buf,
+        session.clone(),
+        config.clone(),
+    ));
+    let mut socks_handler = match handler {
+        Ok(handler) => handler,
+        Err(e) => return reply_malformed(socket, buf, &e, session).instrument(span).await,
+    };
+    if let Err(e) = socks_handler.execute_command().await {
+        error!("Socks error: {}", e);
+    }
+    Ok(())
+}
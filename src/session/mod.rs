@@ -0,0 +1,219 @@
+use crate::access::AccessLog;
+use crate::config::Config;
+use crate::server::{Closed, Extensions};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::{Instant, SystemTime};
+use tokio::sync::watch;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+// Sessions accepted since startup.
+pub fn count() -> u64 {
+    NEXT_ID.load(Ordering::Relaxed) - 1
+}
+
+// Every live session, for the admin API.
+pub static REGISTRY: Registry = Registry::new();
+
+// Facts about one client connection that every stage of the handshake may need.
+#[derive(Debug, Clone)]
+pub struct Session {
+    // Address of the listener that accepted the connection, as configured.
+    pub listener: SocketAddr,
+    pub server_ip_port: SocketAddr,
+    pub client_ip_port: SocketAddr,
+    // Username once the client passed username/password authentication.
+    pub user: Option<String>,
+    // Identity from a verified TLS client certificate.
+    pub certificate: Option<String>,
+    // Shared by every clone; the access log record is written once the last one is gone.
+    pub stats: Arc<Stats>,
+}
+
+impl Session {
+    pub fn new(listener: SocketAddr, server_ip_port: SocketAddr, client_ip_port: SocketAddr, config: &Config) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let stats = Arc::new(Stats {
+            id,
+            client: client_ip_port,
+            started: Instant::now(),
+            start_time: SystemTime::now(),
+            bytes_up: AtomicU64::new(0),
+            bytes_down: AtomicU64::new(0),
+            details: Mutex::new(Details::default()),
+            killed: watch::Sender::new(false),
+            log: config.access_log.clone(),
+            extensions: config.extensions.clone(),
+        });
+        REGISTRY.live.lock().unwrap().insert(id, Arc::downgrade(&stats));
+        Session {
+            listener,
+            server_ip_port,
+            client_ip_port,
+            user: None,
+            certificate: None,
+            stats,
+        }
+    }
+
+    pub fn set_user(&mut self, user: String) {
+        self.stats.details().user = Some(user.clone());
+        self.user = Some(user);
+    }
+}
+
+// What happened to a session so far, filled in as the handshake progresses.
+#[derive(Debug, Clone, Default)]
+pub struct Details {
+    pub user: Option<String>,
+    pub command: Option<&'static str>,
+    // Destination as requested, `host:port`.
+    pub destination: Option<String>,
+    pub resolved: Option<IpAddr>,
+    pub route: Option<String>,
+    pub reply: Option<u8>,
+    pub close: Option<&'static str>,
+}
+
+#[derive(Debug)]
+pub struct Stats {
+    pub id: u64,
+    pub client: SocketAddr,
+    pub started: Instant,
+    pub start_time: SystemTime,
+    // Client to target and back.
+    pub bytes_up: AtomicU64,
+    pub bytes_down: AtomicU64,
+    details: Mutex<Details>,
+    killed: watch::Sender<bool>,
+    log: Option<Arc<AccessLog>>,
+    extensions: Arc<Extensions>,
+}
+
+impl Stats {
+    pub fn details(&self) -> MutexGuard<'_, Details> {
+        self.details.lock().unwrap()
+    }
+
+    // Remember why the session ended; the first reason given wins.
+    pub fn close(&self, reason: &'static str) {
+        self.details().close.get_or_insert(reason);
+    }
+
+    // Ask the connection task to close the session.
+    pub fn kill(&self) {
+        self.killed.send_replace(true);
+    }
+
+    // Resolves once the session was killed.
+    pub async fn killed(&self) {
+        let _ = self.killed.subscribe().wait_for(|killed| *killed).await;
+    }
+}
+
+impl Drop for Stats {
+    fn drop(&mut self) {
+        REGISTRY.finish(self);
+        if let Some(log) = &self.log {
+            log.write(self);
+        }
+        if !self.extensions.hooks.is_empty() {
+            let details = self.details();
+            let closed = Closed {
+                id: self.id,
+                client: self.client,
+                user: details.user.clone(),
+                command: details.command,
+                destination: details.destination.clone(),
+                route: details.route.clone(),
+                reply: details.reply,
+                bytes_up: self.bytes_up.load(Ordering::Relaxed),
+                bytes_down: self.bytes_down.load(Ordering::Relaxed),
+                duration: self.started.elapsed(),
+                close: details.close.unwrap_or("aborted"),
+            };
+            drop(details);
+            for hooks in &self.extensions.hooks {
+                hooks.on_close(&closed);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct UserTotals {
+    pub sessions: u64,
+    pub active: u64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+#[derive(Debug)]
+pub struct Registry {
+    live: Mutex<BTreeMap<u64, Weak<Stats>>>,
+    // Totals of finished sessions, per user.
+    finished: Mutex<BTreeMap<String, UserTotals>>,
+}
+
+impl Registry {
+    const fn new() -> Self {
+        Registry {
+            live: Mutex::new(BTreeMap::new()),
+            finished: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn finish(&self, stats: &Stats) {
+        self.live.lock().unwrap().remove(&stats.id);
+        if let Some(user) = stats.details().user.clone() {
+            let mut finished = self.finished.lock().unwrap();
+            let totals = finished.entry(user).or_default();
+            totals.sessions += 1;
+            totals.bytes_up += stats.bytes_up.load(Ordering::Relaxed);
+            totals.bytes_down += stats.bytes_down.load(Ordering::Relaxed);
+        }
+    }
+
+    // Live sessions, oldest first.
+    pub fn sessions(&self) -> Vec<Arc<Stats>> {
+        // Collected before the lock is released: dropping the last reference
+        // to a session takes the lock again.
+        let live: Vec<Weak<Stats>> = self.live.lock().unwrap().values().cloned().collect();
+        live.iter().filter_map(Weak::upgrade).collect()
+    }
+
+    // Kill one session, false when there is no such session.
+    pub fn kill(&self, id: u64) -> bool {
+        let stats = self.live.lock().unwrap().get(&id).and_then(Weak::upgrade);
+        stats.map(|stats| stats.kill()).is_some()
+    }
+
+    // Kill every session of `user`, returns how many there were.
+    pub fn kill_user(&self, user: &str) -> usize {
+        let sessions: Vec<Arc<Stats>> = self.sessions().into_iter()
+            .filter(|stats| stats.details().user.as_deref() == Some(user))
+            .collect();
+        sessions.iter().for_each(|stats| stats.kill());
+        sessions.len()
+    }
+
+    // Per-user totals over finished and live sessions.
+    pub fn users(&self) -> BTreeMap<String, UserTotals> {
+        let mut users = self.finished.lock().unwrap().clone();
+        for stats in self.sessions() {
+            let Some(user) = stats.details().user.clone() else {
+                continue;
+            };
+            let totals = users.entry(user).or_default();
+            totals.sessions += 1;
+            totals.active += 1;
+            totals.bytes_up += stats.bytes_up.load(Ordering::Relaxed);
+            totals.bytes_down += stats.bytes_down.load(Ordering::Relaxed);
+        }
+        users
+    }
+}
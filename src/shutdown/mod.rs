@@ -0,0 +1,136 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{watch, Notify};
+use tokio::time::{timeout, Duration};
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum State {
+    Running,
+    // No new connections; sessions in flight may finish.
+    Draining,
+    // The drain deadline passed, remaining sessions are being dropped.
+    Closing,
+}
+
+// Tracks live sessions so the server can stop accepting, wait for them and
+// force-close what is left.
+#[derive(Debug)]
+pub struct Shutdown {
+    state: watch::Sender<State>,
+    active: AtomicUsize,
+    idle: Notify,
+    drained: AtomicU64,
+    forced: AtomicU64,
+}
+
+impl Shutdown {
+    pub fn new() -> Arc<Shutdown> {
+        Arc::new(Shutdown {
+            state: watch::Sender::new(State::Running),
+            active: AtomicUsize::new(0),
+            idle: Notify::new(),
+            drained: AtomicU64::new(0),
+            forced: AtomicU64::new(0),
+        })
+    }
+
+    async fn reached(&self, state: State) {
+        let mut rx = self.state.subscribe();
+        let _ = rx.wait_for(|s| *s >= state).await;
+    }
+
+    // Resolves once listeners should stop accepting.
+    pub async fn stopped(&self) {
+        self.reached(State::Draining).await
+    }
+
+    // Resolves once the remaining sessions must be closed.
+    pub async fn forced(&self) {
+        self.reached(State::Closing).await
+    }
+
+    // Register a session; it counts as live until the guard is dropped.
+    pub fn enter(self: &Arc<Self>) -> Guard {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        Guard { shutdown: self.clone() }
+    }
+
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    async fn wait_idle(&self) {
+        loop {
+            let notified = self.idle.notified();
+            if self.active() == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    // Stop accepting, give live sessions up to `deadline` (forever when None)
+    // to finish, then close whatever is left.
+    pub async fn drain(&self, deadline: Option<Duration>) {
+        self.state.send_replace(State::Draining);
+        info!("draining {} sessions", self.active());
+        let finished = match deadline {
+            Some(deadline) => timeout(deadline, self.wait_idle()).await.is_ok(),
+            None => {
+                self.wait_idle().await;
+                true
+            },
+        };
+        if !finished {
+            warn!("drain deadline passed, closing {} sessions", self.active());
+            self.state.send_replace(State::Closing);
+            self.wait_idle().await;
+        }
+        info!(
+            "shutdown complete: {} sessions finished while draining, {} force-closed",
+            self.drained.load(Ordering::Relaxed),
+            self.forced.load(Ordering::Relaxed),
+        );
+    }
+}
+
+pub struct Guard {
+    shutdown: Arc<Shutdown>,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let shutdown = &self.shutdown;
+        match *shutdown.state.borrow() {
+            State::Running => {},
+            State::Draining => {
+                shutdown.drained.fetch_add(1, Ordering::Relaxed);
+            },
+            State::Closing => {
+                shutdown.forced.fetch_add(1, Ordering::Relaxed);
+            },
+        }
+        if shutdown.active.fetch_sub(1, Ordering::Relaxed) == 1 {
+            shutdown.idle.notify_waiters();
+        }
+    }
+}
+
+// Wait for SIGTERM or SIGINT and return the signal's name.
+#[cfg(unix)]
+pub async fn signal() -> std::io::Result<&'static str> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut term = signal(SignalKind::terminate())?;
+    let mut int = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = term.recv() => Ok("SIGTERM"),
+        _ = int.recv() => Ok("SIGINT"),
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn signal() -> std::io::Result<&'static str> {
+    tokio::signal::ctrl_c().await?;
+    Ok("Ctrl-C")
+}
@@ -0,0 +1,142 @@
+use super::methods::{MethodRequest, MethodReply};
+use super::requests::{AuthRequest, SocksRequest};
+use super::replies::{AuthReply, SocksReply, reply_code_from_io_error};
+use super::{SocksAddress, SocksCommand};
+use super::consts;
+use super::traits::*;
+use std::net::{IpAddr, Ipv4Addr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::debug;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("upstream sent unexpected version {0}")]
+    Version(u8),
+    #[error("upstream accepts none of the offered methods")]
+    NoAcceptableMethod,
+    #[error("upstream rejected the credentials")]
+    AuthFailed,
+    #[error("upstream username and password must each be at most 255 bytes")]
+    CredentialsTooLong,
+    #[error("upstream replied with code {0:#04x}")]
+    Rejected(u8),
+}
+
+impl ClientError {
+    // The reply code to hand back to our own client when the upstream fails.
+    pub fn reply_code(&self) -> u8 {
+        match self {
+            ClientError::Rejected(rep) => *rep,
+            ClientError::AuthFailed => consts::SOCKS5_REPLY_CONNECTION_NOT_ALLOWED,
+            ClientError::Io(e) => reply_code_from_io_error(e),
+            _ => consts::SOCKS5_REPLY_GENERAL_FAILURE,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Credentials {
+    // Both are sent with a one-byte length (RFC 1929, and the tunnel header).
+    pub fn check(&self) -> Result<(), ClientError> {
+        match self.username.len() <= 255 && self.password.len() <= 255 {
+            true => Ok(()),
+            false => Err(ClientError::CredentialsTooLong),
+        }
+    }
+}
+
+// Client side of the SOCKS5 handshake, run over any stream that reaches a SOCKS5 server.
+pub struct SocksClient<T: AsyncRead + AsyncWrite + Unpin> {
+    socket: T,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> SocksClient<T> {
+    pub fn new(socket: T) -> Self {
+        Self { socket }
+    }
+
+    async fn negotiate(&mut self, credentials: Option<&Credentials>) -> Result<(), ClientError> {
+        let methods = match credentials {
+            Some(_) => vec![consts::SOCKS5_AUTH_METHOD_NONE, consts::SOCKS5_AUTH_METHOD_PASSWORD],
+            None => vec![consts::SOCKS5_AUTH_METHOD_NONE],
+        };
+        self.socket.write_all(&MethodRequest::new(methods).serialize_to_bytes()).await?;
+        let mut b = [0; 2];
+        self.socket.read_exact(&mut b).await?;
+        let method_reply = MethodReply::deserialize_from_bytes(&b);
+        if method_reply.get_ver() != consts::SOCKS5_VERSION {
+            return Err(ClientError::Version(method_reply.get_ver()));
+        }
+        match (method_reply.get_method(), credentials) {
+            (consts::SOCKS5_AUTH_METHOD_NONE, _) => Ok(()),
+            (consts::SOCKS5_AUTH_METHOD_PASSWORD, Some(c)) => {
+                c.check()?;
+                let auth_request = AuthRequest::new(&c.username, &c.password);
+                self.socket.write_all(&auth_request.serialize_to_bytes()).await?;
+                self.socket.read_exact(&mut b).await?;
+                match AuthReply::deserialize_from_bytes(&b).get_status() {
+                    consts::SOCKS5_AUTH_PASSWORD_SUCCEEDED => Ok(()),
+                    _ => Err(ClientError::AuthFailed),
+                }
+            },
+            _ => Err(ClientError::NoAcceptableMethod),
+        }
+    }
+
+    async fn request(&mut self, cmd: SocksCommand, dst: &SocksAddress, port: u16) -> Result<SocksReply, ClientError> {
+        let socks_request = SocksRequest::new(cmd, dst.clone(), port);
+        self.socket.write_all(&socks_request.serialize_to_bytes()).await?;
+        let reply = read_reply(&mut self.socket).await?;
+        debug!("upstream reply: {:?}", reply);
+        match reply.get_rep() {
+            consts::SOCKS5_REPLY_SUCCEEDED => Ok(reply),
+            rep => Err(ClientError::Rejected(rep)),
+        }
+    }
+
+    // Ask the server to CONNECT to `dst:port` and hand back the stream, now relaying to the target.
+    pub async fn connect(mut self, credentials: Option<&Credentials>, dst: &SocksAddress, port: u16) -> Result<T, ClientError> {
+        self.negotiate(credentials).await?;
+        self.request(SocksCommand::TCPConnect, dst, port).await?;
+        Ok(self.socket)
+    }
+
+    // Ask the server for a UDP ASSOCIATE. Returns the control stream, which must be kept
+    // open for the lifetime of the association, and the relay address reported by the server.
+    pub async fn udp_associate(mut self, credentials: Option<&Credentials>) -> Result<(T, SocksAddress, u16), ClientError> {
+        self.negotiate(credentials).await?;
+        let unspecified = SocksAddress::IP(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        let reply = self.request(SocksCommand::UDPAssociate, &unspecified, 0).await?;
+        Ok((self.socket, reply.get_bnd_address().clone(), reply.get_bnd_port()))
+    }
+}
+
+pub async fn read_reply<T: AsyncRead + Unpin>(socket: &mut T) -> Result<SocksReply, ClientError> {
+    let mut data = vec![0; 4];
+    socket.read_exact(&mut data).await?;
+    if data[0] != consts::SOCKS5_VERSION {
+        return Err(ClientError::Version(data[0]));
+    }
+    let addr_len = match data[3] {
+        consts::SOCKS5_ADDR_TYPE_IPV4 => 4,
+        consts::SOCKS5_ADDR_TYPE_IPV6 => 16,
+        consts::SOCKS5_ADDR_TYPE_DOMAIN_NAME => {
+            let n = socket.read_u8().await?;
+            data.push(n);
+            n as usize
+        },
+        _ => return Err(ClientError::Rejected(consts::SOCKS5_REPLY_ADDRESS_TYPE_NOT_SUPPORTED)),
+    };
+    let start = data.len();
+    data.resize(start + addr_len + 2, 0);
+    socket.read_exact(&mut data[start..]).await?;
+    Ok(SocksReply::deserialize_from_bytes(&data))
+}
@@ -1,18 +1,55 @@
 use super::methods::{MethodRequest, MethodReply};
+use super::requests::{AuthRequest, Socks4Request};
 use super::{SocksCommand, SocksRequest};
-use super::udp::UdpMessage;
-use super::replies::SocksReply;
+use super::udp::{self, UdpMessage};
+use super::replies::{reply_code_from_io_error, socks4_reply_code, AuthReply, Socks4Reply, SocksReply};
+use super::{ParseError, SocksAddress};
 use super::consts;
 use super::traits::*;
-use std::net::SocketAddr;
+use crate::config::{Config, UpstreamUdp};
+use crate::metrics::{method_label, METRICS};
+use crate::outbound::Source;
+use crate::proxy_protocol;
+use crate::http::authority;
+use crate::http::server as http_server;
+use crate::router::{ConnectError, Route};
+use crate::server;
+use crate::session::{Session, Stats};
+use crate::tunnel;
+use crate::upstream::UdpRelay;
+use crate::timeouts::{expired, Phase};
+use super::relay::Tracked;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
-use tokio::time::{sleep, Duration};
-use tokio::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use tokio::time::{sleep, Duration, Instant};
+use tokio::net::{TcpStream, UdpSocket};
 use tokio::sync::mpsc;
-use log::{debug, error, info};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, info_span, Instrument, Span};
 use anyhow::{Result, anyhow};
 
+// Answer a request that does not parse, in the version the client spoke, and
+// end the session.
+pub async fn reply_malformed<T: AsyncWrite + Unpin>(socket: &mut T, data: &[u8], error: &ParseError, session: &Session) -> Result<()> {
+    info!("malformed request from {}: {}", session.client_ip_port, error);
+    let (rep, message) = match data[0] {
+        consts::SOCKS4_VERSION => {
+            let cd = consts::SOCKS4_REPLY_REJECTED;
+            (cd, Socks4Reply::new(cd, session.server_ip_port).serialize_to_bytes())
+        },
+        _ => {
+            let rep = error.reply_code();
+            (rep, SocksReply::new(rep, session.server_ip_port).serialize_to_bytes())
+        },
+    };
+    session.stats.details().reply = Some(rep);
+    session.stats.close("bad_request");
+    socket.write_all(&message).await?;
+    Ok(())
+}
+
 pub struct MethodHandler<T: AsyncRead + AsyncWrite + Unpin> {
     socket: T,
     method_request: MethodRequest,
@@ -23,61 +60,304 @@ impl<T: AsyncRead + AsyncWrite + Unpin> MethodHandler<T> {
         let r: MethodRequest = MethodRequest::deserialize_from_bytes(request);
         debug!("{:?}", r);
         Self {
-            socket: socket,
+            socket,
             method_request: r,
         }
     }
-    pub async fn reply(&mut self) -> Result<()> {
-        let allow_method = match self.method_request.method_exists(0) {
-            true => crate::consts::SOCKS5_AUTH_METHOD_NONE,
-            false => crate::consts::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE,
+    pub async fn reply(&mut self, require_password: bool) -> Result<u8> {
+        let wanted = match require_password {
+            true => consts::SOCKS5_AUTH_METHOD_PASSWORD,
+            false => consts::SOCKS5_AUTH_METHOD_NONE,
+        };
+        let allow_method = match self.method_request.method_exists(wanted) {
+            true => wanted,
+            false => consts::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE,
         };
+        if allow_method == consts::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE {
+            METRICS.handshake(method_label(wanted), "no_method".to_string());
+        }
         let method_reply = MethodReply::new(allow_method);
-        self.socket.write(&method_reply.serialize_to_bytes()).await?;
-        Ok(())
+        self.socket.write_all(&method_reply.serialize_to_bytes()).await?;
+        Ok(allow_method)
     }
 }
 
+pub struct AuthHandler<T: AsyncRead + AsyncWrite + Unpin> {
+    socket: T,
+    auth_request: Result<AuthRequest, ParseError>,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AuthHandler<T> {
+    pub fn new(socket: T, request: &[u8]) -> Self {
+        Self {
+            socket,
+            auth_request: AuthRequest::parse(request),
+        }
+    }
+
+    // Check the credentials and answer the client. Returns the username on
+    // success; a malformed request fails like wrong credentials.
+    pub async fn reply(&mut self, config: &Config) -> Result<Option<String>> {
+        let auth_request = match &self.auth_request {
+            Ok(auth_request) => auth_request,
+            Err(e) => {
+                info!("malformed authentication request: {}", e);
+                let reply = AuthReply::new(consts::SOCKS5_AUTH_PASSWORD_FAILED);
+                self.socket.write_all(&reply.serialize_to_bytes()).await?;
+                METRICS.handshake(method_label(consts::SOCKS5_AUTH_METHOD_PASSWORD), "auth_failed".to_string());
+                return Ok(None);
+            },
+        };
+        let username = auth_request.get_username();
+        let accepted = config.authenticate(username, auth_request.get_password()).await;
+        let status = match accepted {
+            true => consts::SOCKS5_AUTH_PASSWORD_SUCCEEDED,
+            false => consts::SOCKS5_AUTH_PASSWORD_FAILED,
+        };
+        self.socket.write_all(&AuthReply::new(status).serialize_to_bytes()).await?;
+        if !accepted {
+            METRICS.handshake(method_label(consts::SOCKS5_AUTH_METHOD_PASSWORD), "auth_failed".to_string());
+            info!("authentication failed for user {:?}", username);
+            return Ok(None);
+        }
+        debug!("user {:?} authenticated", username);
+        Ok(Some(username.to_string()))
+    }
+}
+
+// The protocol the client spoke, replies are written in it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Socks4,
+    Socks5,
+    Http,
+    // A `socks local` instance on the other end of a tunnel.
+    Tunnel,
+}
+
 pub struct SocksHandler<T: AsyncRead + AsyncWrite + Unpin> {
     socket: T,
+    protocol: Protocol,
     socks_request: SocksRequest,
-    server_ip_port: SocketAddr,
-    client_ip_port: SocketAddr,
+    session: Session,
+    config: Arc<Config>,
+    // Where UDP ASSOCIATE gets the client's datagrams from when they do not
+    // come over UDP, like on a multiplexed tunnel stream.
+    datagrams: Option<tunnel::Datagrams>,
     // socks_reply: SocksReply,
 }
 
 impl<T: AsyncRead + AsyncWrite + Unpin> SocksHandler<T> {
-    pub fn new(socket: T, data: &Vec<u8>, server_ip_port: SocketAddr, client_ip_port: SocketAddr) -> SocksHandler<T> {
-        let data = data.clone();
-        let socks_request = SocksRequest::deserialize_from_bytes(&data);
+    pub fn new(socket: T, data: &[u8], session: Session, config: Arc<Config>) -> Result<SocksHandler<T>, ParseError> {
+        let (protocol, socks_request) = match data[0] {
+            consts::SOCKS4_VERSION => {
+                let request = Socks4Request::parse(data)?;
+                debug!("SOCKS4 user ID {:?}", request.get_userid());
+                (Protocol::Socks4, request.into())
+            },
+            _ => (Protocol::Socks5, SocksRequest::parse(data)?),
+        };
+        Ok(SocksHandler {
+            socket,
+            protocol,
+            socks_request,
+            session,
+            config,
+            datagrams: None,
+        })
+    }
+
+    // A request that came in over HTTP, already parsed and authenticated.
+    pub fn http(socket: T, socks_request: SocksRequest, session: Session, config: Arc<Config>) -> SocksHandler<T> {
         SocksHandler {
-            socket: socket,
-            socks_request: socks_request,
-            server_ip_port: server_ip_port,
-            client_ip_port: client_ip_port,
+            socket,
+            protocol: Protocol::Http,
+            socks_request,
+            session,
+            config,
+            datagrams: None,
         }
     }
 
-    async fn tcp_bind(&mut self) -> Result<()> {
-        let resp = SocksReply::new(consts::SOCKS5_REPLY_COMMAND_NOT_SUPPORTED, self.server_ip_port).serialize_to_bytes();
-        // let resp = self.generate_reply(consts::SOCKS5_REPLY_COMMAND_NOT_SUPPORTED).serialize_to_bytes();
-        if let Err(e) = self.socket.write(&resp).await {
+    // A request read from a tunnel header, the client already authenticated.
+    pub fn tunnel(socket: T, socks_request: SocksRequest, session: Session, config: Arc<Config>) -> SocksHandler<T> {
+        SocksHandler {
+            socket,
+            protocol: Protocol::Tunnel,
+            socks_request,
+            session,
+            config,
+            datagrams: None,
+        }
+    }
+
+    pub fn with_datagrams(mut self, datagrams: tunnel::Datagrams) -> SocksHandler<T> {
+        self.datagrams = Some(datagrams);
+        self
+    }
+
+    // The route, the outbound source, whether to send a PROXY header, and the
+    // address a domain resolved to when the rules needed it.
+    async fn route(&mut self) -> (Route, Source, bool, Option<IpAddr>) {
+        let dst = self.socks_request.get_dst_address();
+        let resolved = match self.config.router.resolves(dst) {
+            true => self.config.extensions.resolve(dst).await.ok(),
+            false => None,
+        };
+        let action = self.config.router.route(&self.session, dst, self.socks_request.get_dst_port(), resolved);
+        let request = server::Request {
+            client: self.session.client_ip_port,
+            listener: self.session.listener,
+            user: self.session.user.clone(),
+            command: self.session.stats.details().command.unwrap_or("connect"),
+            host: self.socks_request.get_dst_address().to_string(),
+            port: self.socks_request.get_dst_port(),
+        };
+        // The embedding program's ACL goes before the rules.
+        let route = match self.config.extensions.allow(&request) {
+            true => &action.route,
+            false => &Route::Block,
+        };
+        self.session.stats.details().route = Some(route.to_string());
+        Span::current().record("route", tracing::field::display(route));
+        if let Route::Block = route {
+            METRICS.acl_denies.fetch_add(1, Ordering::Relaxed);
+        }
+        info!(
+            "{} ({}) -> {}:{} via {}",
+            self.session.client_ip_port,
+            self.session.user.as_deref().unwrap_or("-"),
+            self.socks_request.get_dst_address(),
+            self.socks_request.get_dst_port(),
+            route,
+        );
+        (route.clone(), self.config.source(&self.session, action), action.proxy_protocol, resolved)
+    }
+
+    // Build the reply in the version the client spoke, and count it in the
+    // handshake outcomes, labelled with the method the client used.
+    fn reply_message(&self, rep: u8, bnd_addr: SocketAddr) -> Vec<u8> {
+        let (method, rep, message) = match self.protocol {
+            Protocol::Socks4 => {
+                let cd = socks4_reply_code(rep);
+                ("socks4", cd, Socks4Reply::new(cd, bnd_addr).serialize_to_bytes())
+            },
+            // Counted with the SOCKS5 reply the outcome maps to.
+            Protocol::Http => ("http", rep, http_server::response(rep).into_bytes()),
+            Protocol::Tunnel => ("tunnel", rep, SocksReply::new(rep, bnd_addr).serialize_to_bytes()),
+            Protocol::Socks5 => {
+                let method = match &self.session.user {
+                    Some(user) if self.session.certificate.as_ref() == Some(user) => "certificate",
+                    Some(_) => method_label(consts::SOCKS5_AUTH_METHOD_PASSWORD),
+                    None => method_label(consts::SOCKS5_AUTH_METHOD_NONE),
+                };
+                (method, rep, SocksReply::new(rep, bnd_addr).serialize_to_bytes())
+            },
+        };
+        self.record_reply(method, rep);
+        message
+    }
+
+    fn record_reply(&self, method: &'static str, rep: u8) {
+        METRICS.handshake(method, format!("{:#04x}", rep));
+        self.session.stats.details().reply = Some(rep);
+    }
+
+    fn record_request(&self, command: &'static str) {
+        let mut details = self.session.stats.details();
+        details.command = Some(command);
+        let destination = authority(
+            &self.socks_request.get_dst_address().to_string(),
+            self.socks_request.get_dst_port(),
+        );
+        Span::current().record("destination", destination.as_str());
+        details.destination = Some(destination);
+    }
+
+    async fn reply_failure(&mut self, rep: u8) {
+        let resp = self.reply_message(rep, self.session.server_ip_port);
+        if let Err(e) = self.socket.write_all(&resp).await {
             error!("failed to write to socket; err = {:?}", e);
         }
+    }
+
+    async fn tcp_bind(&mut self) -> Result<()> {
+        self.reply_failure(consts::SOCKS5_REPLY_COMMAND_NOT_SUPPORTED).await;
+        self.session.stats.close("unsupported");
         Err(anyhow!("TCP Bind command not support"))
     }
 
+    // Route the request and connect to the target. Failures are answered here.
+    async fn open(&mut self) -> Result<BoxStream> {
+        let dst_address = self.socks_request.get_dst_address().clone();
+        let dst_port = self.socks_request.get_dst_port();
+        let (route, source, proxy_protocol, resolved) = self.route().await;
+        // Dial the address the rules saw rather than resolving the name again.
+        let dst = match (&route, resolved) {
+            (Route::Direct, Some(ip)) => SocksAddress::IP(ip),
+            _ => dst_address.clone(),
+        };
+        let started = Instant::now();
+        let outbound = self.config.timeouts.run(Phase::Connect, route.connect(&dst, dst_port, &source, &self.config.extensions))
+            .instrument(info_span!("connect", via = %route))
+            .await;
+        let mut outbound_socket = match outbound {
+            Ok(Ok((o, resolved))) => {
+                METRICS.connect_latency.observe(started.elapsed());
+                self.session.stats.details().resolved = resolved;
+                o
+            },
+            Ok(Err(e)) => {
+                self.reply_failure(e.reply_code()).await;
+                self.session.stats.close(match e {
+                    ConnectError::Blocked => "blocked",
+                    _ => "connect_failed",
+                });
+                return Err(e.into());
+            },
+            Err(_) => {
+                self.reply_failure(consts::SOCKS5_REPLY_TTL_EXPIRED).await;
+                self.session.stats.close("connect_timeout");
+                return Err(anyhow!("connect to {}:{} timed out", dst_address, dst_port));
+            },
+        };
+        if proxy_protocol {
+            let header = self.proxy_header();
+            if let Err(e) = outbound_socket.write_all(&header).await {
+                self.reply_failure(reply_code_from_io_error(&e)).await;
+                self.session.stats.close("connect_failed");
+                return Err(e.into());
+            }
+        }
+        Ok(outbound_socket)
+    }
+
+    // PROXY v2 header telling the target who the client is. The target address
+    // is the one we resolved, or the requested IP; unspecified when an
+    // upstream resolved a domain.
+    fn proxy_header(&self) -> Vec<u8> {
+        let client = self.session.client_ip_port;
+        let dst_ip = match (self.session.stats.details().resolved, self.socks_request.get_dst_address()) {
+            (Some(ip), _) => ip,
+            (None, SocksAddress::IP(ip)) => *ip,
+            (None, SocksAddress::Domain(_)) if client.is_ipv4() => Ipv4Addr::UNSPECIFIED.into(),
+            (None, SocksAddress::Domain(_)) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let destination = SocketAddr::new(dst_ip, self.socks_request.get_dst_port());
+        let session_id = self.session.stats.id.to_string();
+        let mut tlvs = vec![(proxy_protocol::PP2_TYPE_UNIQUE_ID, session_id.as_bytes())];
+        if let Some(user) = &self.session.user {
+            tlvs.push((proxy_protocol::PP2_TYPE_USERNAME, user.as_bytes()));
+        }
+        proxy_protocol::v2_header(client, destination, &tlvs)
+    }
+
     async fn tcp_connect(&mut self) -> Result<()> {
-        let socket_addr = SocketAddr::new(
-            self.socks_request.get_dst_addr().await,
-            self.socks_request.get_dst_port()
-        );
-        let outbound_socket = tcp_connect(socket_addr).await.unwrap();
-        // 判斷是否連線成功回傳正確的 res value
+        let outbound_socket = self.open().await?;
         // log 輸出更多的資訊，來源 IP、DST、BND 等等
-        let reply_message = SocksReply::new(consts::SOCKS5_REPLY_SUCCEEDED, self.server_ip_port).serialize_to_bytes();
+        let reply_message = self.reply_message(consts::SOCKS5_REPLY_SUCCEEDED, self.session.server_ip_port);
         // let reply_message = self.generate_reply(consts::SOCKS5_REPLY_SUCCEEDED).serialize_to_bytes();
-        if let Err(e) = self.socket.write(&reply_message).await {
+        if let Err(e) = self.socket.write_all(&reply_message).await {
             error!("failed to write to socket; err = {:?}", e);
             return Err(anyhow!("{}", e));
         }
@@ -86,7 +366,32 @@ impl<T: AsyncRead + AsyncWrite + Unpin> SocksHandler<T> {
             return Err(anyhow!("{}", e));
         }
 
-        transfer(&mut self.socket, outbound_socket).await.unwrap();
+        self.relay(outbound_socket).await;
+        Ok(())
+    }
+
+    async fn relay(&mut self, outbound: BoxStream) {
+        let close = transfer(&mut self.socket, outbound, self.config.timeouts.idle, self.session.stats.clone())
+            .instrument(info_span!("relay", protocol = "tcp"))
+            .await;
+        self.session.stats.close(close);
+    }
+
+    // Plain HTTP forward proxying: send the rewritten request head to the
+    // target, then relay the body and the response as they are.
+    pub async fn forward(&mut self, head: &[u8]) -> Result<()> {
+        let _active = METRICS.session(SocksCommand::TCPConnect);
+        self.record_request("forward");
+        let mut outbound_socket = self.open().await?;
+        if let Err(e) = outbound_socket.write_all(head).await {
+            self.session.stats.close("relay_error");
+            return Err(e.into());
+        }
+        METRICS.bytes_up.fetch_add(head.len() as u64, Ordering::Relaxed);
+        self.session.stats.bytes_up.fetch_add(head.len() as u64, Ordering::Relaxed);
+        // The client gets the target's own response, there is no reply to send.
+        self.record_reply("http", consts::SOCKS5_REPLY_SUCCEEDED);
+        self.relay(outbound_socket).await;
         Ok(())
     }
     
@@ -94,68 +399,232 @@ impl<T: AsyncRead + AsyncWrite + Unpin> SocksHandler<T> {
         // UDP socks request 會設定 type=domain domain=0 python client 是這樣實作的
         // 看起來這個 bound socks proxy -> target 是後面才做的
         // 感覺滿有問題好像可以不顧 TCP request 的 DST.addr 只要使用 UDP client 就可以決定送到哪裡
-        let udp_for_client = UdpSocket::bind(format!("{}:0", self.server_ip_port.ip())).await?;
-        let udp_for_target = UdpSocket::bind("0.0.0.0:0").await?;
-        debug!("UDP listener bound: {:?}", udp_for_client);
+        // 有設定 upstream 時 datagram 原封不動轉給上游的 UDP relay
+        // UDP has no stream to put a PROXY header on.
+        let (route, source, _, _) = self.route().await;
+        let route = match (route, self.config.upstream_udp) {
+            (route @ (Route::Upstream(..) | Route::Pool(_)), UpstreamUdp::Refuse) => {
+                self.reply_failure(consts::SOCKS5_REPLY_CONNECTION_NOT_ALLOWED).await;
+                self.session.stats.close("blocked");
+                return Err(anyhow!("UDP associate routed to {} refused, upstream UDP is off", route));
+            },
+            (route @ (Route::Upstream(..) | Route::Pool(_)), UpstreamUdp::Direct) => {
+                debug!("UDP associate routed to {} is served directly", route);
+                Route::Direct
+            },
+            (route, _) => route,
+        };
+        let associate = self.config.timeouts.run(Phase::Connect, route.udp_associate(&source))
+            .instrument(info_span!("connect", via = %route));
+        let upstream_relay = match associate.await {
+            Ok(Ok(relay)) => relay,
+            Ok(Err(e)) => {
+                self.reply_failure(e.reply_code()).await;
+                self.session.stats.close(match e {
+                    ConnectError::Blocked => "blocked",
+                    _ => "connect_failed",
+                });
+                return Err(e.into());
+            },
+            Err(_) => {
+                self.reply_failure(consts::SOCKS5_REPLY_TTL_EXPIRED).await;
+                self.session.stats.close("connect_timeout");
+                return Err(anyhow!("UDP associate through {} timed out", route));
+            },
+        };
+        let (_upstream_control, relay) = upstream_relay.unzip();
+        // With UDP over TCP the control connection carries the datagrams, through
+        // these channels to and from the relay tasks.
+        let mut pipe = None;
+        let client = match self.datagrams.take() {
+            Some(datagrams) => ClientDatagrams::Tunnel(datagrams, self.session.client_ip_port),
+            None if self.socks_request.udp_over_tcp() => {
+                let (incoming_tx, incoming_rx) = mpsc::channel(50);
+                let (outgoing_tx, outgoing_rx) = mpsc::channel(50);
+                pipe = Some((incoming_tx, outgoing_rx));
+                ClientDatagrams::Stream {
+                    incoming: Arc::new(tokio::sync::Mutex::new(incoming_rx)),
+                    outgoing: outgoing_tx,
+                    client: self.session.client_ip_port,
+                }
+            },
+            None => ClientDatagrams::Socket(Arc::new(UdpSocket::bind(format!("{}:0", self.session.server_ip_port.ip())).await?)),
+        };
+        let udp_for_target = match &relay {
+            Some(UdpRelay::Socket(relay_addr)) => source.udp_bind_for(*relay_addr).await?,
+            _ => source.udp_bind().await?,
+        };
         debug!("UDP listener bound: {:?}", udp_for_target);
         let mut b = [0; 1024];
-        // debug!("{:?}", udp_for_client.local_addr().unwrap());
-        let resp = SocksReply::new(
-            consts::SOCKS5_REPLY_SUCCEEDED,
-            udp_for_client.local_addr().unwrap()
-        ).serialize_to_bytes();
-        if let Err(e) = self.socket.write(&resp).await {
+        let bound = match &client {
+            ClientDatagrams::Socket(socket) => socket.local_addr()?,
+            ClientDatagrams::Tunnel(..) | ClientDatagrams::Stream { .. } => self.session.server_ip_port,
+        };
+        let resp = self.reply_message(consts::SOCKS5_REPLY_SUCCEEDED, bound);
+        if let Err(e) = self.socket.write_all(&resp).await {
             error!("failed to write to socket; err = {:?}", e);
             return Err(anyhow!("{}", e));
         }
-        let aufc = Arc::new(udp_for_client);
-        let aufc2 = aufc.clone();
+        let client2 = client.clone();
         let auft = Arc::new(udp_for_target);
 
         let (tx, mut rx) = mpsc::channel::<(Vec<u8>, SocketAddr)>(50);
-        let rx_handler = tokio::spawn(async move {
+        let stats = self.session.stats.clone();
+        let session = self.session.clone();
+        let config = self.config.clone();
+        let relay_span = info_span!("relay", protocol = "udp");
+        let rx_handler = AbortOnDrop(tokio::spawn(async move {
+            let relayed = || METRICS.udp_relayed.fetch_add(1, Ordering::Relaxed);
+            let up = |n: usize| stats.bytes_up.fetch_add(n as u64, Ordering::Relaxed);
+            let down = |n: usize| stats.bytes_down.fetch_add(n as u64, Ordering::Relaxed);
             while let Some((bytes, addr)) = rx.recv().await {
-                let udp_request = UdpMessage::deserialize_from_bytes(&bytes);
-                let send_data = udp_request.get_udp_data();
-                let send_to_addr = udp_request.get_dst_socket_addr();
-                let _ = auft.send_to(&send_data, send_to_addr).await.unwrap();
-                let (resp_len, _socket_addr) = auft.recv_from(&mut b).await.unwrap();
-                let udp_response = &b[..resp_len];
-                let reply_message = udp_request.generate_reply_message(udp_response.to_vec());
-                aufc2.send_to(&reply_message.serialize_to_bytes(), addr).await.unwrap();
+                let res: std::io::Result<()> = async {
+                    let udp_request = UdpMessage::parse(&bytes)
+                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+                    let dst = udp_request.get_dst_address();
+                    let port = udp_request.get_dst_port();
+                    // Sent from here a datagram needs the address anyway; through
+                    // an upstream only when a CIDR rule has to see it.
+                    let resolved = match (&relay, config.router.resolves(dst)) {
+                        (None, _) => Some(config.extensions.resolve(dst).await?),
+                        (Some(_), true) => config.extensions.resolve(dst).await.ok(),
+                        (Some(_), false) => None,
+                    };
+                    if !datagram_allowed(&config, &session, dst, port, resolved) {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::PermissionDenied,
+                            format!("{} is blocked", authority(&dst.to_string(), port)),
+                        ));
+                    }
+                    match &relay {
+                        Some(UdpRelay::Socket(relay_addr)) => {
+                            up(auft.send_to(&bytes, *relay_addr).await?);
+                            relayed();
+                            let (resp_len, _socket_addr) = auft.recv_from(&mut b).await?;
+                            down(client2.send(&b[..resp_len], addr).await?);
+                            relayed();
+                            return Ok(());
+                        },
+                        Some(UdpRelay::Tunnel(datagrams)) => {
+                            datagrams.send(&bytes).await?;
+                            up(bytes.len());
+                            relayed();
+                            let resp = datagrams.recv().await?;
+                            down(client2.send(&resp, addr).await?);
+                            relayed();
+                            return Ok(());
+                        },
+                        None => {},
+                    }
+                    let send_data = udp_request.get_udp_data();
+                    let send_to_addr = match resolved {
+                        Some(ip) => SocketAddr::new(ip, port),
+                        None => SocketAddr::new(config.extensions.resolve(dst).await?, port),
+                    };
+                    up(auft.send_to(&send_data, send_to_addr).await?);
+                    relayed();
+                    let (resp_len, _socket_addr) = auft.recv_from(&mut b).await?;
+                    let udp_response = &b[..resp_len];
+                    let reply_message = udp_request.generate_reply_message(udp_response.to_vec());
+                    client2.send(&reply_message.serialize_to_bytes(), addr).await?;
+                    down(resp_len);
+                    relayed();
+                    Ok(())
+                }.await;
+                if let Err(e) = res {
+                    debug!("UDP datagram from {} dropped: {}", addr, e);
+                    METRICS.udp_dropped.fetch_add(1, Ordering::Relaxed);
+                }
             }
-        });
+        }.instrument(relay_span.clone())));
         let mut udp_buf = [0; 1024];
-        let tx_handler = tokio::spawn(async move {
+        let started = Instant::now();
+        let last_datagram = Arc::new(AtomicU64::new(0));
+        let last_datagram2 = last_datagram.clone();
+        let tx_handler = AbortOnDrop(tokio::spawn(async move {
             loop {
-                let (len, addr) = aufc.recv_from(&mut udp_buf).await.unwrap();
+                let (len, addr) = match client.recv(&mut udp_buf).await {
+                    Ok(received) => received,
+                    // The tunnel stream or connection is gone, the session ends with it.
+                    Err(e) if !matches!(client, ClientDatagrams::Socket(_)) => {
+                        debug!("UDP receive failed: {}", e);
+                        return;
+                    },
+                    Err(e) => {
+                        debug!("UDP receive failed: {}", e);
+                        continue;
+                    },
+                };
                 debug!("{:?} bytes received from {:?}", len, addr);
-                tx.send((udp_buf[..len].to_vec(), addr)).await.unwrap();
+                last_datagram2.store(started.elapsed().as_secs(), Ordering::Relaxed);
+                // Drop instead of waiting when the relay falls behind.
+                if tx.try_send((udp_buf[..len].to_vec(), addr)).is_err() {
+                    METRICS.udp_dropped.fetch_add(1, Ordering::Relaxed);
+                }
             }
-        });
+        }.instrument(relay_span)));
 
-        loop {
-            sleep(Duration::from_secs(1)).await;
-            match self.socket.write_all(b"ping").await {
-                Ok(_) => {
-                    debug!("ping");
-                },
-                Err(_) => {
-                    debug!("Connection break");
-                    rx_handler.abort();
-                    tx_handler.abort();
-                    break;
-                },
+        let idle = self.config.timeouts.idle;
+        let idle_timer = async {
+            loop {
+                sleep(Duration::from_secs(1)).await;
+                if let Some(idle) = idle {
+                    let quiet = started.elapsed().as_secs() - last_datagram.load(Ordering::Relaxed);
+                    if quiet >= idle.as_secs() {
+                        expired(Phase::Idle);
+                        return "idle_timeout";
+                    }
+                }
             }
-        }
+        };
+        let reason = match pipe {
+            Some((incoming, outgoing)) => tokio::select! {
+                reason = idle_timer => reason,
+                reason = pipe_datagrams(&mut self.socket, incoming, outgoing) => reason,
+            },
+            None => tokio::select! {
+                reason = idle_timer => reason,
+                reason = keep_alive(&mut self.socket) => reason,
+            },
+        };
+        self.session.stats.close(reason);
+        rx_handler.abort();
+        tx_handler.abort();
         debug!("udp finsh!");
         Ok(())
     }
 
     pub async fn execute_command(&mut self) -> Result<()> {
         let cmd = self.socks_request.get_cmd();
-        if self.socks_request.get_ver() != 5 {
-            panic!("wrong socks version!");
+        let _active = METRICS.session(cmd);
+        self.record_request(cmd.as_str());
+        match (self.protocol, self.socks_request.get_ver()) {
+            (Protocol::Http, _) | (Protocol::Socks5, consts::SOCKS5_VERSION) => {},
+            // SOCKS4 has no way to send a password.
+            (Protocol::Socks4, _) if self.session.user.is_none() && self.config.password_required() => {
+                self.reply_failure(consts::SOCKS4_REPLY_IDENTD_MISMATCH).await;
+                self.session.stats.close("auth_failed");
+                return Err(anyhow!("SOCKS4 request from {} but a password is required", self.session.client_ip_port));
+            },
+            (Protocol::Socks4, _) if cmd == SocksCommand::UDPAssociate => {
+                self.reply_failure(consts::SOCKS5_REPLY_COMMAND_NOT_SUPPORTED).await;
+                self.session.stats.close("unsupported");
+                return Err(anyhow!("SOCKS4 has no UDP associate command"));
+            },
+            (Protocol::Socks4, _) => {},
+            // UDP needs a multiplexed tunnel to carry the datagrams.
+            (Protocol::Tunnel, _) if cmd == SocksCommand::TCPBind
+                || (cmd == SocksCommand::UDPAssociate && self.datagrams.is_none()) => {
+                self.reply_failure(consts::SOCKS5_REPLY_COMMAND_NOT_SUPPORTED).await;
+                self.session.stats.close("unsupported");
+                return Err(anyhow!("tunnel does not carry {}", cmd.as_str()));
+            },
+            (Protocol::Tunnel, consts::SOCKS5_VERSION) => {},
+            (_, ver) => {
+                self.reply_failure(consts::SOCKS5_REPLY_GENERAL_FAILURE).await;
+                self.session.stats.close("unsupported");
+                return Err(anyhow!("unsupported SOCKS version {}", ver));
+            },
         }
         match cmd {
             SocksCommand::TCPBind => {
@@ -177,29 +646,187 @@ impl<T: AsyncRead + AsyncWrite + Unpin> SocksHandler<T> {
 }
 
 
-async fn transfer<I, O>(mut inbound: I, mut outbound: O) -> Result<()>
+// Whether the ACL and the rules let a datagram of an association go to `dst`,
+// which resolved to `resolved` if it was looked up. Datagrams that pass take
+// the association's route, whatever route their destination would get.
+fn datagram_allowed(config: &Config, session: &Session, dst: &SocksAddress, port: u16, resolved: Option<IpAddr>) -> bool {
+    let request = server::Request {
+        client: session.client_ip_port,
+        listener: session.listener,
+        user: session.user.clone(),
+        command: SocksCommand::UDPAssociate.as_str(),
+        host: dst.to_string(),
+        port,
+    };
+    config.extensions.allow(&request)
+        && !matches!(config.router.route(session, dst, port, resolved).route, Route::Block)
+}
+
+// Where a UDP association gets its client's datagrams from and sends the
+// replies back to.
+#[derive(Clone)]
+enum ClientDatagrams {
+    Socket(Arc<UdpSocket>),
+    // Carried by the tunnel from the `socks local` instance at this address.
+    Tunnel(tunnel::Datagrams, SocketAddr),
+    // Framed on the control connection of the client at this address.
+    Stream {
+        incoming: Arc<tokio::sync::Mutex<mpsc::Receiver<Vec<u8>>>>,
+        outgoing: mpsc::Sender<Vec<u8>>,
+        client: SocketAddr,
+    },
+}
+
+impl ClientDatagrams {
+    async fn recv(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        match self {
+            ClientDatagrams::Socket(socket) => socket.recv_from(buf).await,
+            ClientDatagrams::Tunnel(datagrams, client) => {
+                let datagram = datagrams.recv().await?;
+                let n = datagram.len().min(buf.len());
+                buf[..n].copy_from_slice(&datagram[..n]);
+                Ok((n, *client))
+            },
+            ClientDatagrams::Stream { incoming, client, .. } => {
+                let datagram = incoming.lock().await.recv().await
+                    .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::ConnectionAborted))?;
+                let n = datagram.len().min(buf.len());
+                buf[..n].copy_from_slice(&datagram[..n]);
+                Ok((n, *client))
+            },
+        }
+    }
+
+    async fn send(&self, datagram: &[u8], addr: SocketAddr) -> std::io::Result<usize> {
+        match self {
+            ClientDatagrams::Socket(socket) => socket.send_to(datagram, addr).await,
+            ClientDatagrams::Tunnel(datagrams, _) => {
+                datagrams.send(datagram).await?;
+                Ok(datagram.len())
+            },
+            ClientDatagrams::Stream { outgoing, .. } => {
+                outgoing.send(datagram.to_vec()).await
+                    .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+                Ok(datagram.len())
+            },
+        }
+    }
+}
+
+// Tell a client of a UDP association that it is still there, until the
+// control connection closes.
+async fn keep_alive<S: AsyncWrite + Unpin>(socket: &mut S) -> &'static str {
+    loop {
+        sleep(Duration::from_secs(1)).await;
+        match socket.write_all(b"ping").await {
+            Ok(_) => {
+                debug!("ping");
+            },
+            Err(_) => {
+                debug!("Connection break");
+                return "client_closed";
+            },
+        }
+    }
+}
+
+// UDP over TCP: move framed datagrams between the control connection and
+// the relay tasks until the client closes it.
+async fn pipe_datagrams<S>(socket: &mut S, incoming: mpsc::Sender<Vec<u8>>, mut outgoing: mpsc::Receiver<Vec<u8>>) -> &'static str
 where
-    I: AsyncRead + AsyncWrite + Unpin,
-    O: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
 {
-    match tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await {
-        Ok(res) => info!("transfer closed ({}, {})", res.0, res.1),
-        Err(err) => error!("transfer error: {:?}", err),
+    let (mut reader, mut writer) = tokio::io::split(socket);
+    let read = async {
+        loop {
+            match udp::read_frame(&mut reader).await {
+                Ok(Some(datagram)) => {
+                    if incoming.send(datagram).await.is_err() {
+                        return "relay_error";
+                    }
+                },
+                Ok(None) => return "client_closed",
+                Err(e) => {
+                    debug!("UDP over TCP read failed: {}", e);
+                    return "client_error";
+                },
+            }
+        }
     };
+    let write = async {
+        while let Some(datagram) = outgoing.recv().await {
+            if let Err(e) = writer.write_all(&udp::frame(&datagram)).await {
+                debug!("UDP over TCP write failed: {}", e);
+                return "client_closed";
+            }
+        }
+        "relay_error"
+    };
+    tokio::select! {
+        reason = read => reason,
+        reason = write => reason,
+    }
+}
 
-    Ok(())
+// Background task of a session, stopped with it even when the session is
+// dropped rather than finishing (killed or force-closed).
+struct AbortOnDrop(JoinHandle<()>);
+
+impl AbortOnDrop {
+    fn abort(&self) {
+        self.0.abort();
+    }
+}
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
 }
 
-pub async fn tcp_connect<T>(addr: T) -> Result<TcpStream>
-    where T: ToSocketAddrs,
+// Relay until either side closes or nothing moves for `idle`. Returns why it stopped.
+async fn transfer<I, O>(inbound: I, mut outbound: O, idle: Option<Duration>, stats: Arc<Stats>) -> &'static str
+where
+    I: AsyncRead + AsyncWrite + Unpin,
+    O: AsyncRead + AsyncWrite + Unpin,
 {
-    match TcpStream::connect(addr).await {
+    let mut inbound = Tracked::new(inbound, stats);
+    let idle_timer = idle.map(|idle| inbound.idle(idle));
+    let idle_timer = async {
+        match idle_timer {
+            Some(idle_timer) => idle_timer.await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        res = tokio::io::copy_bidirectional(&mut inbound, &mut outbound) => match res {
+            Ok(res) => {
+                info!("transfer closed ({}, {})", res.0, res.1);
+                "completed"
+            },
+            // TLS clients often close without a close_notify.
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                debug!("transfer closed: {}", err);
+                "completed"
+            },
+            Err(err) => {
+                error!("transfer error: {:?}", err);
+                "relay_error"
+            },
+        },
+        _ = idle_timer => {
+            expired(Phase::Idle);
+            "idle_timeout"
+        },
+    }
+}
+
+pub async fn tcp_connect(addr: SocketAddr, source: &Source) -> std::io::Result<TcpStream> {
+    match source.connect(addr).await {
         Ok(o) => {
             info!("connect successful.");
             Ok(o)
         },
-        Err(e) => match e.kind() {
-            _ => Err(e.into()), // #[error("General failure")] ?
-        },
+        Err(e) => Err(e),
     }
 }
\ No newline at end of file
@@ -1,4 +1,5 @@
-use log::debug;
+use tracing::debug;
+use super::consts;
 use super::traits::*;
 
 #[derive(Debug)]
@@ -10,30 +11,38 @@ pub struct MethodRequest {
 }
 
 impl SocksPacket for MethodRequest {
+    // A truncated greeting offers only the methods it carries, possibly none.
     fn deserialize_from_bytes(bytes: &[u8]) -> MethodRequest {
-        let n_methods = bytes[1];
-        let end: usize = n_methods as usize + 2;
+        let n_methods = bytes.get(1).copied().unwrap_or(0);
+        let methods = bytes.get(2..).unwrap_or_default();
+        let methods = &methods[..methods.len().min(n_methods as usize)];
         MethodRequest {
             ver: bytes[0],
-            n_methods: n_methods,
-            methods: bytes[2..end].to_vec(),
+            n_methods: methods.len() as u8,
+            methods: methods.to_vec(),
         }
     }
     
     fn serialize_to_bytes(&self) -> Vec<u8> {
-        todo!()
+        let mut s = vec![self.ver, self.n_methods];
+        s.extend(&self.methods);
+        s
     }
 }
 
 // TODO for impl trait
 impl MethodRequest {
-    pub fn method_exists(&self, method: u8) -> bool {
-        if self.methods.contains(&method) {
-            true
-        } else {
-            false
+    pub fn new(methods: Vec<u8>) -> MethodRequest {
+        MethodRequest {
+            ver: consts::SOCKS5_VERSION,
+            n_methods: methods.len() as u8,
+            methods,
         }
     }
+
+    pub fn method_exists(&self, method: u8) -> bool {
+        self.methods.contains(&method)
+    }
 }
 /*
 o  X'00' NO AUTHENTICATION REQUIRED
@@ -56,6 +65,14 @@ impl MethodReply {
             method,
         }
     }
+
+    pub fn get_ver(&self) -> u8 {
+        self.ver
+    }
+
+    pub fn get_method(&self) -> u8 {
+        self.method
+    }
 }
 
 impl SocksPacket for MethodReply {
@@ -65,6 +82,9 @@ impl SocksPacket for MethodReply {
     }
     
     fn deserialize_from_bytes(bytes: &[u8]) -> Self {
-        todo!()
+        MethodReply {
+            ver: bytes[0],
+            method: bytes[1],
+        }
     }
-}
\ No newline at end of file
+}
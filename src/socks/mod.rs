@@ -5,18 +5,23 @@ pub mod client;
 pub mod udp;
 pub mod traits;
 pub mod handlers;
+pub mod relay;
 
 // use serde::Serialize;
-use log::debug;
+use tracing::{debug, info_span, Instrument};
 use tokio::net::lookup_host;
+use crate::metrics::METRICS;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
 use traits::*;
 use super::consts;
 use std::array::TryFromSliceError;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use requests::SocksRequest;
 use anyhow::Result;
+use thiserror::Error;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum SocksCommand {
     TCPConnect,
     TCPBind,
@@ -27,24 +32,57 @@ pub enum SocksCommand {
 impl SocksCommand {
     #[inline]
     #[rustfmt::skip]
-    fn as_u8(&self) -> u8 {
+    pub fn as_u8(&self) -> u8 {
         match self {
             SocksCommand::TCPConnect   => consts::SOCKS5_CMD_TCP_CONNECT,
             SocksCommand::TCPBind      => consts::SOCKS5_CMD_TCP_BIND,
             SocksCommand::UDPAssociate => consts::SOCKS5_CMD_UDP_ASSOCIATE,
         }
     }
+
+    // Name used in logs and metric labels.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SocksCommand::TCPConnect => "connect",
+            SocksCommand::TCPBind => "bind",
+            SocksCommand::UDPAssociate => "udp_associate",
+        }
+    }
 }
 
-impl From<u8> for SocksCommand {
-    fn from(number: u8) -> SocksCommand {
+impl TryFrom<u8> for SocksCommand {
+    type Error = ParseError;
+
+    fn try_from(number: u8) -> Result<SocksCommand, ParseError> {
         match number {
-            consts::SOCKS5_CMD_TCP_CONNECT      => SocksCommand::TCPConnect,
-            consts::SOCKS5_CMD_TCP_BIND         => SocksCommand::TCPBind,
-            consts::SOCKS5_CMD_UDP_ASSOCIATE    => SocksCommand::UDPAssociate,
-            _ => {
-                panic!("run socks5 command");
-            },
+            consts::SOCKS5_CMD_TCP_CONNECT      => Ok(SocksCommand::TCPConnect),
+            consts::SOCKS5_CMD_TCP_BIND         => Ok(SocksCommand::TCPBind),
+            consts::SOCKS5_CMD_UDP_ASSOCIATE    => Ok(SocksCommand::UDPAssociate),
+            _ => Err(ParseError::Command(number)),
+        }
+    }
+}
+
+// A client message that does not parse.
+#[derive(Debug, Error)]
+pub enum ParseError {
+    #[error("message is truncated")]
+    Truncated,
+    #[error("unknown command {0:#04x}")]
+    Command(u8),
+    #[error("unknown address type {0:#04x}")]
+    AddressType(u8),
+    #[error("{0} is not NUL terminated")]
+    Unterminated(&'static str),
+}
+
+impl ParseError {
+    // The SOCKS5 reply that tells the client what was wrong.
+    pub fn reply_code(&self) -> u8 {
+        match self {
+            ParseError::Command(_) => consts::SOCKS5_REPLY_COMMAND_NOT_SUPPORTED,
+            ParseError::AddressType(_) => consts::SOCKS5_REPLY_ADDRESS_TYPE_NOT_SUPPORTED,
+            _ => consts::SOCKS5_REPLY_GENERAL_FAILURE,
         }
     }
 }
@@ -65,7 +103,7 @@ impl SocksPacket for SocksPort {
     }
     
     fn deserialize_from_bytes(bytes: &[u8]) -> Self {
-        todo!()
+        SocksPort(calculate_port_number(bytes[0], bytes[1]).unwrap())
     }
 }
 impl From<SocksPort> for u16 {
@@ -81,28 +119,60 @@ pub enum SocksAddress {
 }
 
 impl SocksAddress {
-    async fn get_ip_addr(&self) -> IpAddr {
+    pub fn get_atyp(&self) -> u8 {
+        match self {
+            SocksAddress::IP(IpAddr::V4(_)) => consts::SOCKS5_ADDR_TYPE_IPV4,
+            SocksAddress::IP(IpAddr::V6(_)) => consts::SOCKS5_ADDR_TYPE_IPV6,
+            SocksAddress::Domain(_) => consts::SOCKS5_ADDR_TYPE_DOMAIN_NAME,
+        }
+    }
+    pub async fn get_ip_addr(&self) -> std::io::Result<IpAddr> {
         match self {
             SocksAddress::IP(ip_addr) => {
-                *ip_addr
+                Ok(*ip_addr)
             },
             SocksAddress::Domain(domain) => {
-                let mut ip_addr: Option<IpAddr> = None;
                 let address = format!("{}:666", domain);
-                for addr in lookup_host(address).await.unwrap() {
-                    println!("socket address is {}", addr);
-                    ip_addr = Some(addr.ip());
-                    break;
-                }
-                match ip_addr {
-                    Some(addr) => {
-                        addr
+                let started = Instant::now();
+                let resolved = lookup_host(address)
+                    .instrument(info_span!("dns", host = %domain))
+                    .await
+                    .map(|mut addrs| addrs.next().map(|addr| addr.ip()));
+                METRICS.dns_latency.observe(started.elapsed());
+                debug!("{} resolved to {:?}", domain, resolved);
+                match resolved {
+                    Ok(Some(ip_addr)) => Ok(ip_addr),
+                    Ok(None) => {
+                        METRICS.dns_failures.fetch_add(1, Ordering::Relaxed);
+                        Err(std::io::Error::new(
+                            std::io::ErrorKind::HostUnreachable,
+                            "can not resolve domain name.",
+                        ))
+                    },
+                    Err(e) => {
+                        METRICS.dns_failures.fetch_add(1, Ordering::Relaxed);
+                        Err(e)
                     },
-                    _ => { panic!("can not resolve domain name."); }
                 }
             }
         }
     }
+    // Parse the address at the start of `bytes`, returning it with the rest.
+    pub fn parse(atyp: u8, bytes: &[u8]) -> Result<(SocksAddress, &[u8]), ParseError> {
+        let (len, start) = match atyp {
+            consts::SOCKS5_ADDR_TYPE_IPV4 => (4, 0),
+            consts::SOCKS5_ADDR_TYPE_IPV6 => (16, 0),
+            consts::SOCKS5_ADDR_TYPE_DOMAIN_NAME => (*bytes.first().ok_or(ParseError::Truncated)? as usize, 1),
+            _ => return Err(ParseError::AddressType(atyp)),
+        };
+        let address = bytes.get(start..start + len).ok_or(ParseError::Truncated)?;
+        let address = match atyp {
+            consts::SOCKS5_ADDR_TYPE_IPV4 => SocksAddress::IP(IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(address).unwrap()))),
+            consts::SOCKS5_ADDR_TYPE_IPV6 => SocksAddress::IP(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(address).unwrap()))),
+            _ => SocksAddress::Domain(address.iter().map(|b| *b as char).collect()),
+        };
+        Ok((address, &bytes[start + len..]))
+    }
     pub fn parse_dst_address(atyp: u8, data: &mut Vec<u8>) -> SocksAddress {
         match atyp {
             consts::SOCKS5_ADDR_TYPE_IPV4 => {
@@ -145,13 +215,24 @@ impl SocksAddress {
                 }
             },
             SocksAddress::Domain(domain) => {
-                todo!();
+                let mut d = vec![domain.len() as u8];
+                d.extend(domain.as_bytes());
+                d
             }
         };
         s
     }
 }
 
+impl std::fmt::Display for SocksAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SocksAddress::IP(ip) => write!(f, "{}", ip),
+            SocksAddress::Domain(domain) => write!(f, "{}", domain),
+        }
+    }
+}
+
 fn calculate_port_number(first: u8, second: u8) -> Option<u16> {
     let o: Vec<u8> = vec![first, second];
     if o.len() == 2 {
@@ -0,0 +1,84 @@
+use crate::metrics::METRICS;
+use crate::session::Stats;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, Duration, Instant};
+
+// Wraps the client side of a relay, remembers when bytes last moved in either
+// direction and counts them, globally and for the session: reads are sent up
+// to the target, writes come down.
+pub struct Tracked<S> {
+    inner: S,
+    start: Instant,
+    // Milliseconds since `start` of the last read or write.
+    last_active: Arc<AtomicU64>,
+    stats: Arc<Stats>,
+}
+
+impl<S> Tracked<S> {
+    pub fn new(inner: S, stats: Arc<Stats>) -> Self {
+        Tracked {
+            inner,
+            start: Instant::now(),
+            last_active: Arc::new(AtomicU64::new(0)),
+            stats,
+        }
+    }
+
+    fn touch(&self) {
+        self.last_active.store(self.start.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    // Resolves once nothing moved for `idle`.
+    pub fn idle(&self, idle: Duration) -> impl std::future::Future<Output = ()> + 'static {
+        let (start, last_active) = (self.start, self.last_active.clone());
+        async move {
+            loop {
+                let deadline = start + Duration::from_millis(last_active.load(Ordering::Relaxed)) + idle;
+                if deadline <= Instant::now() {
+                    return;
+                }
+                sleep(deadline - Instant::now()).await;
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tracked<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let n = buf.filled().len() - before;
+        if n > 0 {
+            METRICS.bytes_up.fetch_add(n as u64, Ordering::Relaxed);
+            self.stats.bytes_up.fetch_add(n as u64, Ordering::Relaxed);
+            self.touch();
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tracked<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            if n > 0 {
+                METRICS.bytes_down.fetch_add(n as u64, Ordering::Relaxed);
+                self.stats.bytes_down.fetch_add(n as u64, Ordering::Relaxed);
+                self.touch();
+            }
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -1,10 +1,43 @@
-use std::net::{IpAddr, SocketAddr, Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use super::consts;
-use log::debug;
+use tracing::debug;
 use super::{SocksAddress, SocksPort};
 use super::traits::*;
 
-struct AuthReply {}
+// +----+--------+
+// |VER | STATUS |
+// +----+--------+
+// | 1  |   1    |
+// +----+--------+
+#[derive(Debug)]
+pub struct AuthReply {
+    ver: u8,
+    status: u8,
+}
+
+impl SocksPacket for AuthReply {
+    fn deserialize_from_bytes(bytes: &[u8]) -> Self {
+        AuthReply {
+            ver: bytes[0],
+            status: bytes[1],
+        }
+    }
+    fn serialize_to_bytes(&self) -> Vec<u8> {
+        vec![self.ver, self.status]
+    }
+}
+
+impl AuthReply {
+    pub fn new(status: u8) -> AuthReply {
+        AuthReply {
+            ver: consts::SOCKS5_AUTH_PASSWORD_VERSION,
+            status,
+        }
+    }
+    pub fn get_status(&self) -> u8 {
+        self.status
+    }
+}
 
 /*
 o  REP    Reply field:
@@ -20,6 +53,18 @@ o  REP    Reply field:
     o  X'09' to X'FF' unassigned
 */
 
+// Pick the reply field that best describes a failed outbound connection.
+pub fn reply_code_from_io_error(e: &std::io::Error) -> u8 {
+    match e.kind() {
+        std::io::ErrorKind::ConnectionRefused => consts::SOCKS5_REPLY_CONNECTION_REFUSED,
+        std::io::ErrorKind::NetworkUnreachable => consts::SOCKS5_REPLY_NETWORK_UNREACHABLE,
+        std::io::ErrorKind::HostUnreachable => consts::SOCKS5_REPLY_HOST_UNREACHABLE,
+        std::io::ErrorKind::PermissionDenied => consts::SOCKS5_REPLY_CONNECTION_NOT_ALLOWED,
+        std::io::ErrorKind::TimedOut => consts::SOCKS5_REPLY_TTL_EXPIRED,
+        _ => consts::SOCKS5_REPLY_GENERAL_FAILURE,
+    }
+}
+
 #[derive(Debug)]
 pub struct SocksReply {
     ver: u8,
@@ -32,7 +77,20 @@ pub struct SocksReply {
 
 impl SocksPacket for SocksReply {
     fn deserialize_from_bytes(bytes: &[u8]) -> Self {
-        todo!()
+        let mut data = bytes.to_vec();
+        let ver = data.remove(0);
+        let rep = data.remove(0);
+        let rsv = data.remove(0);
+        let atyp = data.remove(0);
+        let bnd_addr = SocksAddress::parse_dst_address(atyp, &mut data);
+        SocksReply {
+            ver,
+            rep,
+            rsv,
+            atyp,
+            bnd_addr,
+            bnd_port: SocksPort::deserialize_from_bytes(&data),
+        }
     }
     fn serialize_to_bytes(&self) -> Vec<u8> {
         let mut s = vec![self.ver, self.rep, self.rsv, self.atyp];
@@ -48,20 +106,20 @@ impl SocksReply {
             IpAddr::V4(ipv4) => {
                 SocksReply {
                     ver: consts::SOCKS5_VERSION,
-                    rep: rep,
+                    rep,
                     rsv: 0,
                     atyp: consts::SOCKS5_ADDR_TYPE_IPV4,
-                    bnd_addr: SocksAddress::IP(IpAddr::V4(Ipv4Addr::from(ipv4))),
+                    bnd_addr: SocksAddress::IP(IpAddr::V4(ipv4)),
                     bnd_port: SocksPort::new(socks_addr.port()),
                 }
             },
             IpAddr::V6(ipv6) => {
                 SocksReply {
                     ver: consts::SOCKS5_VERSION,
-                    rep: rep,
+                    rep,
                     rsv: 0,
                     atyp: consts::SOCKS5_ADDR_TYPE_IPV6,
-                    bnd_addr: SocksAddress::IP(IpAddr::V6(Ipv6Addr::from(ipv6))),
+                    bnd_addr: SocksAddress::IP(IpAddr::V6(ipv6)),
                     bnd_port: SocksPort::new(socks_addr.port()),
                 }
             },
@@ -69,4 +127,67 @@ impl SocksReply {
         debug!("{:?}", reply_message);
         reply_message
     }
-}
\ No newline at end of file
+    pub fn get_rep(&self) -> u8 {
+        self.rep
+    }
+    pub fn get_bnd_address(&self) -> &SocksAddress {
+        &self.bnd_addr
+    }
+    pub fn get_bnd_port(&self) -> u16 {
+        self.bnd_port.into()
+    }
+}
+
+// +----+----+---------+-------+
+// | VN | CD | DSTPORT | DSTIP |
+// +----+----+---------+-------+
+// | 1  | 1  |    2    |   4   |
+// +----+----+---------+-------+
+#[derive(Debug)]
+pub struct Socks4Reply {
+    cd: u8,
+    dst_port: SocksPort,
+    dst_ip: Ipv4Addr,
+}
+
+impl SocksPacket for Socks4Reply {
+    fn deserialize_from_bytes(bytes: &[u8]) -> Self {
+        Socks4Reply {
+            cd: bytes[1],
+            dst_port: SocksPort::deserialize_from_bytes(&bytes[2..4]),
+            dst_ip: Ipv4Addr::new(bytes[4], bytes[5], bytes[6], bytes[7]),
+        }
+    }
+    fn serialize_to_bytes(&self) -> Vec<u8> {
+        let mut s = vec![consts::SOCKS4_REPLY_VERSION, self.cd];
+        s.extend(self.dst_port.serialize_to_bytes());
+        s.extend(self.dst_ip.octets());
+        s
+    }
+}
+
+impl Socks4Reply {
+    // SOCKS4 can not carry an IPv6 address, those are sent as 0.0.0.0.
+    pub fn new(cd: u8, socks_addr: SocketAddr) -> Socks4Reply {
+        let dst_ip = match socks_addr.ip() {
+            IpAddr::V4(ipv4) => ipv4,
+            IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+        };
+        let reply_message = Socks4Reply {
+            cd,
+            dst_port: SocksPort::new(socks_addr.port()),
+            dst_ip,
+        };
+        debug!("{:?}", reply_message);
+        reply_message
+    }
+}
+
+// SOCKS4 only tells granted from rejected, SOCKS4 codes are kept as they are.
+pub fn socks4_reply_code(rep: u8) -> u8 {
+    match rep {
+        consts::SOCKS5_REPLY_SUCCEEDED => consts::SOCKS4_REPLY_GRANTED,
+        consts::SOCKS4_REPLY_GRANTED..=consts::SOCKS4_REPLY_IDENTD_MISMATCH => rep,
+        _ => consts::SOCKS4_REPLY_REJECTED,
+    }
+}
@@ -1,55 +1,299 @@
-use super::{SocksCommand, SocksAddress, SocksPort, calculate_port_number};
-use log::{debug, info};
-use std::net::IpAddr;
+use super::{ParseError, SocksCommand, SocksAddress, SocksPort};
+use super::consts;
+use tracing::debug;
 use super::traits::*;
+use std::net::{IpAddr, Ipv4Addr};
 
 #[derive(Debug)]
 #[allow(dead_code)]
 pub struct SocksRequest {
     ver: u8,
     cmd: SocksCommand,
+    rsv: u8,
     atyp: u8,
     dst_address: SocksAddress,
     dst_port: SocksPort,
 }
 
 impl SocksPacket for SocksRequest {
+    // Panics on malformed input, requests from clients go through `parse`.
     fn deserialize_from_bytes(bytes: &[u8]) -> SocksRequest {
-        debug!("socks request content: {:?}", bytes);
-        let mut data = bytes.to_vec();
-        let ver = data.remove(0);
-        let command = SocksCommand::from(data.remove(0));
-        let _rsv: u8 = data.remove(0);
-        let atyp: u8 = data.remove(0);
-        //let dst_address: SocksAddress = match atyp {
-        let dst_address = SocksAddress::parse_dst_address(atyp, &mut data);
-        let port = calculate_port_number(data.remove(0), data.remove(0)).unwrap();
-        let socks_request = SocksRequest {
-            ver: ver,
-            cmd: command,
-            atyp: atyp,
-            dst_address: dst_address,
-            dst_port: SocksPort::new(port),
-        };
-        debug!("{:?}", socks_request);
-        socks_request
+        SocksRequest::parse(bytes).expect("malformed SOCKS5 request")
     }
     fn serialize_to_bytes(&self) -> Vec<u8> {
-        todo!()
+        let mut s = vec![self.ver, self.cmd.as_u8(), self.rsv, self.atyp];
+        s.extend(self.dst_address.serialize_to_bytes());
+        s.extend(self.dst_port.serialize_to_bytes());
+        s
     }
 }
 
 impl SocksRequest {
-    pub async fn get_dst_addr(&self) -> IpAddr {
-        self.dst_address.get_ip_addr().await
+    pub fn parse(bytes: &[u8]) -> Result<SocksRequest, ParseError> {
+        debug!("socks request content: {:?}", bytes);
+        if bytes.len() < 4 {
+            return Err(ParseError::Truncated);
+        }
+        let (ver, rsv, atyp) = (bytes[0], bytes[2], bytes[3]);
+        let cmd = SocksCommand::try_from(bytes[1])?;
+        let (dst_address, rest) = SocksAddress::parse(atyp, &bytes[4..])?;
+        let port = rest.get(..2).ok_or(ParseError::Truncated)?;
+        let socks_request = SocksRequest {
+            ver,
+            cmd,
+            rsv,
+            atyp,
+            dst_address,
+            dst_port: SocksPort::new(u16::from_be_bytes([port[0], port[1]])),
+        };
+        debug!("{:?}", socks_request);
+        Ok(socks_request)
+    }
+    pub fn new(cmd: SocksCommand, dst_address: SocksAddress, dst_port: u16) -> SocksRequest {
+        SocksRequest {
+            ver: consts::SOCKS5_VERSION,
+            cmd,
+            rsv: 0,
+            atyp: dst_address.get_atyp(),
+            dst_address,
+            dst_port: SocksPort::new(dst_port),
+        }
+    }
+    pub fn get_dst_address(&self) -> &SocksAddress {
+        &self.dst_address
     }
     pub fn get_dst_port(&self) -> u16 {
         self.dst_port.into()
     }
     pub fn get_cmd(&self) -> SocksCommand {
-        self.cmd.clone()
+        self.cmd
     }
     pub fn get_ver(&self) -> u8 {
         self.ver
     }
-}
\ No newline at end of file
+    // A UDP ASSOCIATE whose datagrams come over this TCP connection. The RSV
+    // bit is our own extension, not RFC 1928, which requires RSV to be zero;
+    // other servers ignore it or refuse the request.
+    pub fn udp_over_tcp(&self) -> bool {
+        self.cmd == SocksCommand::UDPAssociate && self.rsv & consts::SOCKS5_FLAG_UDP_OVER_TCP != 0
+    }
+}
+
+impl From<Socks4Request> for SocksRequest {
+    fn from(request: Socks4Request) -> SocksRequest {
+        SocksRequest {
+            ver: consts::SOCKS4_VERSION,
+            cmd: request.cmd,
+            rsv: 0,
+            atyp: request.dst_address.get_atyp(),
+            dst_address: request.dst_address,
+            dst_port: request.dst_port,
+        }
+    }
+}
+
+// +----+----+---------+-------+----------+------+
+// | VN | CD | DSTPORT | DSTIP |  USERID  | NULL |
+// +----+----+---------+-------+----------+------+
+// | 1  | 1  |    2    |   4   | variable |  1   |
+// +----+----+---------+-------+----------+------+
+// SOCKS4a: a DSTIP of 0.0.0.x with x != 0 means the host name follows the
+// USERID, NUL terminated as well.
+#[derive(Debug)]
+pub struct Socks4Request {
+    cmd: SocksCommand,
+    dst_address: SocksAddress,
+    dst_port: SocksPort,
+    userid: String,
+}
+
+impl SocksPacket for Socks4Request {
+    // Panics on malformed input, requests from clients go through `parse`.
+    fn deserialize_from_bytes(bytes: &[u8]) -> Socks4Request {
+        Socks4Request::parse(bytes).expect("malformed SOCKS4 request")
+    }
+    fn serialize_to_bytes(&self) -> Vec<u8> {
+        let mut s = vec![consts::SOCKS4_VERSION, self.cmd.as_u8()];
+        s.extend(self.dst_port.serialize_to_bytes());
+        match &self.dst_address {
+            SocksAddress::IP(IpAddr::V4(ip)) => s.extend(ip.octets()),
+            _ => s.extend([0, 0, 0, 1]),
+        }
+        s.extend(self.userid.as_bytes());
+        s.push(0);
+        match &self.dst_address {
+            SocksAddress::IP(IpAddr::V4(_)) => {},
+            other => {
+                s.extend(other.to_string().as_bytes());
+                s.push(0);
+            },
+        }
+        s
+    }
+}
+
+impl Socks4Request {
+    pub fn parse(bytes: &[u8]) -> Result<Socks4Request, ParseError> {
+        debug!("socks4 request content: {:?}", bytes);
+        if bytes.len() < 8 {
+            return Err(ParseError::Truncated);
+        }
+        // SOCKS4 has CONNECT and BIND only.
+        let cmd = match bytes[1] {
+            consts::SOCKS5_CMD_TCP_CONNECT | consts::SOCKS5_CMD_TCP_BIND => SocksCommand::try_from(bytes[1])?,
+            cd => return Err(ParseError::Command(cd)),
+        };
+        let port = u16::from_be_bytes([bytes[2], bytes[3]]);
+        let ip = Ipv4Addr::new(bytes[4], bytes[5], bytes[6], bytes[7]);
+        let mut rest = &bytes[8..];
+        let mut next = |field| {
+            let end = rest.iter().position(|b| *b == 0).ok_or(ParseError::Unterminated(field))?;
+            let value = String::from_utf8_lossy(&rest[..end]).to_string();
+            rest = &rest[end + 1..];
+            Ok(value)
+        };
+        let userid = next("user ID")?;
+        let dst_address = match ip.octets() {
+            [0, 0, 0, x] if x != 0 => SocksAddress::Domain(next("host name")?),
+            _ => SocksAddress::IP(IpAddr::V4(ip)),
+        };
+        let socks4_request = Socks4Request {
+            cmd,
+            dst_address,
+            dst_port: SocksPort::new(port),
+            userid,
+        };
+        debug!("{:?}", socks4_request);
+        Ok(socks4_request)
+    }
+    pub fn get_userid(&self) -> &str {
+        &self.userid
+    }
+}
+
+// +----+------+----------+------+----------+
+// |VER | ULEN |  UNAME   | PLEN |  PASSWD  |
+// +----+------+----------+------+----------+
+// | 1  |  1   | 1 to 255 |  1   | 1 to 255 |
+// +----+------+----------+------+----------+
+// RFC 1929 username/password sub-negotiation
+#[derive(Debug)]
+pub struct AuthRequest {
+    ver: u8,
+    username: String,
+    password: String,
+}
+
+impl SocksPacket for AuthRequest {
+    // Panics on malformed input, requests from clients go through `parse`.
+    fn deserialize_from_bytes(bytes: &[u8]) -> AuthRequest {
+        AuthRequest::parse(bytes).expect("malformed authentication request")
+    }
+    fn serialize_to_bytes(&self) -> Vec<u8> {
+        let mut s = vec![self.ver, self.username.len() as u8];
+        s.extend(self.username.as_bytes());
+        s.push(self.password.len() as u8);
+        s.extend(self.password.as_bytes());
+        s
+    }
+}
+
+impl AuthRequest {
+    pub fn parse(bytes: &[u8]) -> Result<AuthRequest, ParseError> {
+        let field = |start: usize| -> Result<(String, usize), ParseError> {
+            let len = *bytes.get(start).ok_or(ParseError::Truncated)? as usize;
+            let value = bytes.get(start + 1..start + 1 + len).ok_or(ParseError::Truncated)?;
+            Ok((String::from_utf8_lossy(value).to_string(), start + 1 + len))
+        };
+        let ver = *bytes.first().ok_or(ParseError::Truncated)?;
+        let (username, next) = field(1)?;
+        let (password, _) = field(next)?;
+        Ok(AuthRequest {
+            ver,
+            username,
+            password,
+        })
+    }
+    pub fn new(username: &str, password: &str) -> AuthRequest {
+        AuthRequest {
+            ver: consts::SOCKS5_AUTH_PASSWORD_VERSION,
+            username: username.to_string(),
+            password: password.to_string(),
+        }
+    }
+    pub fn get_username(&self) -> &str {
+        &self.username
+    }
+    pub fn get_password(&self) -> &str {
+        &self.password
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn socks5_request() {
+        let request = SocksRequest::parse(b"\x05\x01\x00\x03\x0bexample.com\x01\xbb").unwrap();
+        assert_eq!(request.get_cmd(), SocksCommand::TCPConnect);
+        assert_eq!(request.get_dst_address().to_string(), "example.com");
+        assert_eq!(request.get_dst_port(), 443);
+        let request = SocksRequest::parse(&SocksRequest::new(SocksCommand::UDPAssociate, SocksAddress::IP("::1".parse().unwrap()), 53).serialize_to_bytes()).unwrap();
+        assert_eq!(request.get_dst_address().to_string(), "::1");
+        assert_eq!(request.get_dst_port(), 53);
+    }
+
+    #[test]
+    fn malformed_socks5_requests() {
+        assert!(matches!(SocksRequest::parse(b"\x05\x01\x00"), Err(ParseError::Truncated)));
+        assert!(matches!(SocksRequest::parse(b"\x05\x01\x00\x01\x7f\x00\x00"), Err(ParseError::Truncated)));
+        assert!(matches!(SocksRequest::parse(b"\x05\x01\x00\x03\x0bexample.com\x01"), Err(ParseError::Truncated)));
+        let unknown_command = SocksRequest::parse(b"\x05\x09\x00\x01\x7f\x00\x00\x01\x00\x50").unwrap_err();
+        assert_eq!(unknown_command.reply_code(), consts::SOCKS5_REPLY_COMMAND_NOT_SUPPORTED);
+        let unknown_atyp = SocksRequest::parse(b"\x05\x01\x00\x02\x7f\x00\x00\x01\x00\x50").unwrap_err();
+        assert_eq!(unknown_atyp.reply_code(), consts::SOCKS5_REPLY_ADDRESS_TYPE_NOT_SUPPORTED);
+    }
+
+    #[test]
+    fn socks4_request() {
+        let request = Socks4Request::parse(b"\x04\x01\x00\x50\x0a\x00\x00\x01alice\x00").unwrap();
+        assert_eq!(request.cmd, SocksCommand::TCPConnect);
+        assert_eq!(request.dst_address.to_string(), "10.0.0.1");
+        assert_eq!(u16::from(request.dst_port), 80);
+        assert_eq!(request.get_userid(), "alice");
+    }
+
+    #[test]
+    fn socks4a_request() {
+        let request = Socks4Request::parse(b"\x04\x01\x01\xbb\x00\x00\x00\x01\x00example.com\x00").unwrap();
+        assert!(matches!(&request.dst_address, SocksAddress::Domain(host) if host == "example.com"));
+        assert_eq!(u16::from(request.dst_port), 443);
+        assert_eq!(request.get_userid(), "");
+        assert_eq!(Socks4Request::parse(&request.serialize_to_bytes()).unwrap().dst_address.to_string(), "example.com");
+    }
+
+    #[test]
+    fn malformed_socks4_requests() {
+        assert!(matches!(Socks4Request::parse(b"\x04\x01"), Err(ParseError::Truncated)));
+        assert!(matches!(Socks4Request::parse(b"\x04\x01\x00\x50\x0a\x00\x00\x01"), Err(ParseError::Unterminated(_))));
+        assert!(matches!(Socks4Request::parse(b"\x04\x01\x00\x50\x0a\x00\x00\x01alice"), Err(ParseError::Unterminated(_))));
+        assert!(matches!(Socks4Request::parse(b"\x04\x01\x00\x50\x00\x00\x00\x01\x00example.com"), Err(ParseError::Unterminated(_))));
+        assert!(matches!(Socks4Request::parse(b"\x04\x03\x00\x50\x0a\x00\x00\x01\x00"), Err(ParseError::Command(3))));
+    }
+
+    #[test]
+    fn auth_request() {
+        let request = AuthRequest::parse(b"\x01\x05alice\x06secret").unwrap();
+        assert_eq!(request.get_username(), "alice");
+        assert_eq!(request.get_password(), "secret");
+        assert_eq!(AuthRequest::parse(&request.serialize_to_bytes()).unwrap().get_password(), "secret");
+    }
+
+    #[test]
+    fn malformed_auth_requests() {
+        for bytes in [&b""[..], b"\x01", b"\x01\x05al", b"\x01\x05alice", b"\x01\x05alice\x06sec"] {
+            assert!(matches!(AuthRequest::parse(bytes), Err(ParseError::Truncated)), "{:?}", bytes);
+        }
+    }
+}
@@ -1,4 +1,14 @@
+use tokio::io::{AsyncRead, AsyncWrite};
+
 pub trait SocksPacket {
     fn deserialize_from_bytes(bytes: &[u8]) -> Self;
     fn serialize_to_bytes(&self) -> Vec<u8>;
-}
\ No newline at end of file
+}
+
+/// Any byte stream a session can be relayed over.
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+/// A boxed `AsyncStream`, what a `Connector` returns.
+pub type BoxStream = Box<dyn AsyncStream>;
@@ -1,8 +1,6 @@
-use super::{SocksAddress, SocksPort};
-use super::calculate_port_number;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use log::{debug, info};
+use super::{ParseError, SocksAddress, SocksPort};
 use super::traits::*;
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 // +----+------+------+----------+----------+----------+
 // |RSV | FRAG | ATYP | DST.ADDR | DST.PORT |   DATA   |
@@ -21,6 +19,7 @@ use super::traits::*;
 //     o  DATA     user data
 
 #[derive(Debug)]
+#[allow(dead_code)]
 pub struct UdpMessage {
     rsv: u16,
     frag: u8,
@@ -31,16 +30,33 @@ pub struct UdpMessage {
 }
 
 impl UdpMessage {
+    pub fn parse(bytes: &[u8]) -> Result<UdpMessage, ParseError> {
+        if bytes.len() < 4 {
+            return Err(ParseError::Truncated);
+        }
+        let (frag, atyp) = (bytes[2], bytes[3]);
+        let (dst_addr, rest) = SocksAddress::parse(atyp, &bytes[4..])?;
+        let port = rest.get(..2).ok_or(ParseError::Truncated)?;
+        Ok(UdpMessage {
+            rsv: 0,
+            frag,
+            atyp,
+            dst_addr,
+            dst_port: SocksPort::new(u16::from_be_bytes([port[0], port[1]])),
+            data: rest[2..].to_vec(),
+        })
+    }
+
     pub fn get_udp_data(&self) -> Vec<u8> {
         self.data.clone()
     }
 
-    pub fn _get_dst_port(&self) -> u16 {
-        self.dst_port.into()
+    pub fn get_dst_address(&self) -> &SocksAddress {
+        &self.dst_addr
     }
 
-    pub fn get_dst_socket_addr(&self) -> SocketAddr {
-        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)), 53)
+    pub fn get_dst_port(&self) -> u16 {
+        self.dst_port.into()
     }
 
     pub fn generate_reply_message(&self, data: Vec<u8>) -> Self {
@@ -49,30 +65,17 @@ impl UdpMessage {
             frag: self.frag,
             atyp: self.atyp,
             dst_addr: self.dst_addr.clone(),
-            dst_port: self.dst_port.clone(),
-            data: data,
+            dst_port: self.dst_port,
+            data,
 
         }
     }
 }
 
 impl SocksPacket for UdpMessage {
+    // Panics on malformed input, datagrams from clients go through `parse`.
     fn deserialize_from_bytes(bytes: &[u8]) -> Self {
-        let mut data = bytes.to_vec();
-        let _rsv: Vec<u8> = data.drain(0..2).collect(); // 保留位元組，不處理
-        let frag: u8 = data.remove(0);
-        let atyp: u8 = data.remove(0);
-        let dst_address = SocksAddress::parse_dst_address(atyp, &mut data);
-        let port = calculate_port_number(data.remove(0), data.remove(0)).unwrap();
-        let udp_data: &[u8] = &data[0..];
-        Self {
-            rsv: 0,
-            frag: frag,
-            atyp: atyp,
-            dst_addr: dst_address,
-            dst_port: SocksPort::new(port),
-            data: udp_data.to_vec(),
-        }
+        UdpMessage::parse(bytes).expect("malformed UDP datagram")
     }
     fn serialize_to_bytes(&self) -> Vec<u8> {
         let mut data: Vec<u8> = vec![
@@ -85,4 +88,30 @@ impl SocksPacket for UdpMessage {
         data.extend(self.data.clone());
         data
     }
-}
\ No newline at end of file
+}
+
+// UDP over TCP: the same datagrams, each prefixed with its length, on the
+// connection of the UDP ASSOCIATE request.
+// +--------+------------------------------+
+// |  LEN   | RSV | FRAG | ATYP | ... DATA |
+// +--------+------------------------------+
+// |   2    |           LEN bytes          |
+// +--------+------------------------------+
+pub fn frame(datagram: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(2 + datagram.len());
+    framed.extend_from_slice(&(datagram.len() as u16).to_be_bytes());
+    framed.extend_from_slice(datagram);
+    framed
+}
+
+// The next framed datagram, None when the connection closes between two.
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<Option<Vec<u8>>> {
+    let len = match reader.read_u16().await {
+        Ok(len) => len,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut datagram = vec![0; len as usize];
+    reader.read_exact(&mut datagram).await?;
+    Ok(Some(datagram))
+}
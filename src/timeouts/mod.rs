@@ -0,0 +1,90 @@
+use crate::metrics::METRICS;
+use std::fmt;
+use std::future::Future;
+use tokio::time::{error::Elapsed, timeout, Duration};
+use tracing::info;
+
+// Stages of a session that can time out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    Greeting,
+    Auth,
+    Request,
+    Connect,
+    Idle,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 5] = [Phase::Greeting, Phase::Auth, Phase::Request, Phase::Connect, Phase::Idle];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phase::Greeting => "greeting",
+            Phase::Auth => "auth",
+            Phase::Request => "request",
+            Phase::Connect => "connect",
+            Phase::Idle => "idle",
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+// Per-phase limits, None means no limit.
+#[derive(Debug, Clone)]
+pub struct Timeouts {
+    pub greeting: Option<Duration>,
+    pub auth: Option<Duration>,
+    pub request: Option<Duration>,
+    pub connect: Option<Duration>,
+    pub idle: Option<Duration>,
+    // How long shutdown waits for live sessions before closing them.
+    pub drain: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            greeting: Some(Duration::from_secs(10)),
+            auth: Some(Duration::from_secs(10)),
+            request: Some(Duration::from_secs(10)),
+            connect: Some(Duration::from_secs(10)),
+            idle: Some(Duration::from_secs(300)),
+            drain: Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+impl Timeouts {
+    pub fn get(&self, phase: Phase) -> Option<Duration> {
+        match phase {
+            Phase::Greeting => self.greeting,
+            Phase::Auth => self.auth,
+            Phase::Request => self.request,
+            Phase::Connect => self.connect,
+            Phase::Idle => self.idle,
+        }
+    }
+
+    // Run `fut` under the limit for `phase`, counting the timeout when it fires.
+    pub async fn run<F: Future>(&self, phase: Phase, fut: F) -> Result<F::Output, Elapsed> {
+        let Some(limit) = self.get(phase) else {
+            return Ok(fut.await);
+        };
+        let res = timeout(limit, fut).await;
+        if res.is_err() {
+            expired(phase);
+        }
+        res
+    }
+}
+
+// Record a timeout that was detected outside of `Timeouts::run`.
+pub fn expired(phase: Phase) {
+    let total = METRICS.timeout(phase);
+    info!("{} timeout ({} so far)", phase, total);
+}
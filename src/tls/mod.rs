@@ -0,0 +1,337 @@
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::{client, TlsAcceptor, TlsConnector};
+use tracing::{debug, info, warn};
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::{FromDer, X509Certificate};
+use anyhow::{anyhow, Context, Error, Result};
+
+// How often certificate and key files are checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CertPaths {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl CertPaths {
+    fn load(&self) -> Result<CertifiedKey> {
+        let certs = read_certs(&self.cert)?;
+        let key: PrivateKeyDer = rustls_pemfile::private_key(&mut read(&self.key)?.as_slice())
+            .with_context(|| format!("can not parse {}", self.key.display()))?
+            .ok_or_else(|| anyhow!("no private key in {}", self.key.display()))?;
+        let key = ring::sign::any_supported_type(&key)
+            .with_context(|| format!("unsupported private key in {}", self.key.display()))?;
+        Ok(CertifiedKey::new(certs, key))
+    }
+
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        Some((modified(&self.cert)?, modified(&self.key)?))
+    }
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut read(path)?.as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("can not parse {}", path.display()))?;
+    if certs.is_empty() {
+        return Err(anyhow!("no certificate in {}", path.display()));
+    }
+    Ok(certs)
+}
+
+fn read(path: &Path) -> Result<Vec<u8>> {
+    std::fs::read(path).with_context(|| format!("can not read {}", path.display()))
+}
+
+// When client certificates are asked for, and what else a client needs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClientAuth {
+    // A certificate is verified when sent; without one, SOCKS auth applies as usual.
+    Optional,
+    // A certificate is required and its identity is the user.
+    Required,
+    // A certificate is required and the client still authenticates with
+    // username/password, which gives the user.
+    RequiredWithPassword,
+}
+
+impl FromStr for ClientAuth {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "optional" => Ok(ClientAuth::Optional),
+            "required" => Ok(ClientAuth::Required),
+            "required+password" => Ok(ClientAuth::RequiredWithPassword),
+            _ => Err(anyhow!("unknown client_auth {:?}, expected optional, required or required+password", s)),
+        }
+    }
+}
+
+// Which part of a client certificate names the client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Identity {
+    // Common name of the subject, the whole subject when it has none.
+    Subject,
+    // First DNS name, e-mail address or URI of the subject alternative names.
+    San,
+}
+
+impl FromStr for Identity {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "subject" => Ok(Identity::Subject),
+            "san" => Ok(Identity::San),
+            _ => Err(anyhow!("unknown identity {:?}, expected subject or san", s)),
+        }
+    }
+}
+
+impl Identity {
+    fn of(&self, cert: &CertificateDer) -> Option<String> {
+        let (_, cert) = X509Certificate::from_der(cert).ok()?;
+        match self {
+            Identity::Subject => {
+                let subject = cert.subject();
+                match subject.iter_common_name().next() {
+                    Some(cn) => cn.as_str().ok().map(str::to_string),
+                    None => Some(subject.to_string()),
+                }
+            },
+            Identity::San => cert.subject_alternative_name().ok()??.value.general_names.iter()
+                .find_map(|name| match name {
+                    GeneralName::DNSName(s) | GeneralName::RFC822Name(s) | GeneralName::URI(s) => Some(s.to_string()),
+                    _ => None,
+                }),
+        }
+    }
+}
+
+// Client certificate verification, the [tls] client_ca setting and friends.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientCerts {
+    pub ca: PathBuf,
+    pub auth: ClientAuth,
+    pub identity: Identity,
+}
+
+// The [tls] section: listeners that speak TLS first, the default certificate
+// and per-SNI ones.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub listen: Vec<SocketAddr>,
+    pub default: CertPaths,
+    pub sni: HashMap<String, CertPaths>,
+    pub client: Option<ClientCerts>,
+}
+
+struct Loaded {
+    default: Arc<CertifiedKey>,
+    sni: HashMap<String, Arc<CertifiedKey>>,
+    modified: Vec<Option<(SystemTime, SystemTime)>>,
+}
+
+// Picks the certificate by SNI, falling back to the default one, and swaps
+// them when the files change on disk.
+#[derive(Debug)]
+pub struct CertResolver {
+    settings: Settings,
+    loaded: RwLock<Loaded>,
+}
+
+impl std::fmt::Debug for Loaded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Loaded").field("sni", &self.sni.keys()).finish()
+    }
+}
+
+impl CertResolver {
+    fn new(settings: Settings) -> Result<Self> {
+        let loaded = CertResolver::load(&settings)?;
+        Ok(CertResolver { settings, loaded: RwLock::new(loaded) })
+    }
+
+    fn paths(settings: &Settings) -> impl Iterator<Item = &CertPaths> {
+        std::iter::once(&settings.default).chain(settings.sni.values())
+    }
+
+    fn load(settings: &Settings) -> Result<Loaded> {
+        // Timestamps are taken first so a write during loading is seen next time.
+        let modified = CertResolver::paths(settings).map(|p| p.modified()).collect();
+        Ok(Loaded {
+            default: Arc::new(settings.default.load()?),
+            sni: settings.sni.iter()
+                .map(|(name, paths)| Ok((name.to_ascii_lowercase(), Arc::new(paths.load()?))))
+                .collect::<Result<_>>()?,
+            modified,
+        })
+    }
+
+    // Re-read every certificate when one of the files changed. A broken
+    // file keeps the previous certificates in use.
+    fn reload_if_changed(&self) {
+        let modified: Vec<_> = CertResolver::paths(&self.settings).map(|p| p.modified()).collect();
+        if modified == self.loaded.read().unwrap().modified {
+            return;
+        }
+        match CertResolver::load(&self.settings) {
+            Ok(loaded) => {
+                *self.loaded.write().unwrap() = loaded;
+                info!("TLS certificates reloaded");
+            },
+            Err(e) => warn!("can not reload TLS certificates: {:#}", e),
+        }
+    }
+
+    fn watch(self: &Arc<Self>) {
+        let resolver = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(WATCH_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                match resolver.upgrade() {
+                    Some(resolver) => resolver.reload_if_changed(),
+                    None => return,
+                }
+            }
+        });
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let loaded = self.loaded.read().unwrap();
+        let by_name = client_hello.server_name()
+            .and_then(|name| loaded.sni.get(&name.to_ascii_lowercase()));
+        debug!("TLS client hello for {:?}", client_hello.server_name());
+        Some(by_name.unwrap_or(&loaded.default).clone())
+    }
+}
+
+// A client that presented a verified certificate.
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub identity: String,
+    // The certificate alone does not authenticate the client.
+    pub password_required: bool,
+}
+
+// Terminates TLS on the TLS listeners.
+#[derive(Clone)]
+pub struct Acceptor {
+    inner: TlsAcceptor,
+    client: Option<ClientCerts>,
+}
+
+impl Acceptor {
+    // Load the certificates and the client CA bundle.
+    pub fn new(settings: &Settings) -> Result<Self> {
+        let resolver = Arc::new(CertResolver::new(settings.clone())?);
+        resolver.watch();
+        let provider = Arc::new(ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let config = match &settings.client {
+            None => builder.with_no_client_auth(),
+            Some(client) => {
+                let mut roots = RootCertStore::empty();
+                for cert in read_certs(&client.ca)? {
+                    roots.add(cert).with_context(|| format!("bad CA certificate in {}", client.ca.display()))?;
+                }
+                let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+                let verifier = match client.auth {
+                    ClientAuth::Optional => verifier.allow_unauthenticated(),
+                    ClientAuth::Required | ClientAuth::RequiredWithPassword => verifier,
+                };
+                builder.with_client_cert_verifier(verifier.build()?)
+            },
+        };
+        Ok(Acceptor {
+            inner: TlsAcceptor::from(Arc::new(config.with_cert_resolver(resolver))),
+            client: settings.client.clone(),
+        })
+    }
+
+    // Run the handshake. A verified client certificate without a usable
+    // identity fails it.
+    pub async fn accept(&self, socket: TcpStream) -> std::io::Result<(TlsStream<TcpStream>, Option<Peer>)> {
+        let stream = self.inner.accept(socket).await?;
+        let peer = self.peer(stream.get_ref().1.peer_certificates())?;
+        Ok((stream, peer))
+    }
+
+    // The client behind a verified certificate chain, if it sent one.
+    pub fn peer(&self, certs: Option<&[CertificateDer<'_>]>) -> std::io::Result<Option<Peer>> {
+        let (client, cert) = match (&self.client, certs) {
+            (Some(client), Some([cert, ..])) => (client, cert),
+            _ => return Ok(None),
+        };
+        let identity = client.identity.of(cert).ok_or_else(|| std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "no identity in the client certificate",
+        ))?;
+        Ok(Some(Peer {
+            identity,
+            password_required: client.auth == ClientAuth::RequiredWithPassword,
+        }))
+    }
+
+    // The same certificates and client verification, offering `alpn`.
+    pub fn server_config(&self, alpn: &[u8]) -> ServerConfig {
+        let mut config = (**self.inner.config()).clone();
+        config.alpn_protocols = vec![alpn.to_vec()];
+        config
+    }
+}
+
+// Client side TLS for upstreams, verified against the webpki roots plus the
+// certificates in $SSL_CERT_FILE when it is set.
+pub fn client_config() -> Arc<ClientConfig> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    CONFIG.get_or_init(|| {
+        let mut roots = RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.into() };
+        if let Some(path) = std::env::var_os("SSL_CERT_FILE") {
+            match read_certs(Path::new(&path)) {
+                Ok(certs) => {
+                    let (added, _) = roots.add_parsable_certificates(certs);
+                    debug!("{} CA certificates added from {}", added, Path::new(&path).display());
+                },
+                Err(e) => warn!("{:#}", e),
+            }
+        }
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .expect("ring supports the default protocol versions")
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Arc::new(config)
+    }).clone()
+}
+
+pub async fn connect<S>(stream: S, host: &str) -> std::io::Result<client::TlsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let name = ServerName::try_from(host.to_string())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    TlsConnector::from(client_config()).connect(name, stream).await
+}
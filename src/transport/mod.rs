@@ -0,0 +1,51 @@
+pub mod websocket;
+
+use crate::socks::traits::BoxStream;
+use crate::tls;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tracing::{info_span, Instrument};
+
+// What a listener speaks below SOCKS, or instead of it for tunnels.
+#[derive(Clone)]
+pub enum Transport {
+    Plain,
+    Tls(tls::Acceptor),
+    // Binary WebSocket messages on `path`, over TLS when an acceptor is set.
+    WebSocket { path: Arc<str>, tls: Option<tls::Acceptor> },
+    // TLS carrying the tunnel protocol from `socks local` instead of SOCKS.
+    Tunnel(tls::Acceptor),
+}
+
+impl Transport {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Transport::Plain => "TCP",
+            Transport::Tls(_) => "TLS",
+            Transport::WebSocket { tls: None, .. } => "WebSocket",
+            Transport::WebSocket { tls: Some(_), .. } => "WebSocket over TLS",
+            Transport::Tunnel(_) => "tunnel",
+        }
+    }
+
+    // Run the transport handshakes and return the stream SOCKS is spoken over,
+    // with the verified client certificate if there was one.
+    pub async fn accept(&self, socket: TcpStream) -> std::io::Result<(BoxStream, Option<tls::Peer>)> {
+        match self {
+            Transport::Plain => Ok((Box::new(socket), None)),
+            Transport::Tls(acceptor) | Transport::Tunnel(acceptor) => {
+                let (stream, peer) = acceptor.accept(socket).instrument(info_span!("tls")).await?;
+                Ok((Box::new(stream), peer))
+            },
+            Transport::WebSocket { path, tls: None } => {
+                let stream = websocket::accept(socket, path).instrument(info_span!("websocket")).await?;
+                Ok((Box::new(stream), None))
+            },
+            Transport::WebSocket { path, tls: Some(acceptor) } => {
+                let (stream, peer) = acceptor.accept(socket).instrument(info_span!("tls")).await?;
+                let stream = websocket::accept(stream, path).instrument(info_span!("websocket")).await?;
+                Ok((Box::new(stream), peer))
+            },
+        }
+    }
+}
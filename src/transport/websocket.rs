@@ -0,0 +1,160 @@
+use futures_util::{Sink, Stream};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::error::ProtocolError;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::WebSocketStream;
+
+// The [websocket] section: listeners that take WebSocket upgrades on `path`
+// and run SOCKS over the binary messages, behind TLS when `tls` is set.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub listen: Vec<SocketAddr>,
+    pub path: String,
+    pub tls: bool,
+}
+
+// Server side: answer the upgrade request when it is for `path`, 404 otherwise.
+pub async fn accept<S>(stream: S, path: &str) -> std::io::Result<WsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // The callback signature is tungstenite's.
+    #[allow(clippy::result_large_err)]
+    let check_path = |request: &Request, response: Response| {
+        if request.uri().path() == path {
+            return Ok(response);
+        }
+        let mut error = ErrorResponse::new(None);
+        *error.status_mut() = StatusCode::NOT_FOUND;
+        Err(error)
+    };
+    let inner = tokio_tungstenite::accept_hdr_async(stream, check_path).await.map_err(io_error)?;
+    Ok(WsStream::new(inner))
+}
+
+// Client side: upgrade a stream already connected to `host:port`.
+pub async fn connect<S>(stream: S, host: &str, port: u16, path: &str, secure: bool) -> std::io::Result<WsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let scheme = if secure { "wss" } else { "ws" };
+    let url = format!("{}://{}{}", scheme, crate::http::authority(host, port), path);
+    let (inner, _) = tokio_tungstenite::client_async(url, stream).await.map_err(io_error)?;
+    Ok(WsStream::new(inner))
+}
+
+fn io_error(e: WsError) -> std::io::Error {
+    match e {
+        WsError::Io(e) => e,
+        e => std::io::Error::other(e),
+    }
+}
+
+// Byte stream over WebSocket binary messages: every write is sent as one
+// message, messages are read back to back. WebSocket has no half-close, so
+// shutting down the write side sends an empty message instead of a Close
+// frame and the peer reads it as end of stream.
+pub struct WsStream<S> {
+    inner: WebSocketStream<S>,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    read_eof: bool,
+    // A message was queued but not flushed yet.
+    unflushed: bool,
+    write_eof: bool,
+}
+
+impl<S> WsStream<S> {
+    fn new(inner: WebSocketStream<S>) -> Self {
+        WsStream { inner, read_buf: Vec::new(), read_pos: 0, read_eof: false, unflushed: false, write_eof: false }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WsStream<S> {
+    fn poll_send_pending(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        if self.unflushed {
+            ready!(Pin::new(&mut self.inner).poll_flush(cx)).map_err(io_error)?;
+            self.unflushed = false;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        // Keep a queued write moving while the other side waits for it.
+        if let Poll::Ready(Err(e)) = self.poll_send_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+        loop {
+            if self.read_eof {
+                return Poll::Ready(Ok(()));
+            }
+            if self.read_pos < self.read_buf.len() {
+                let n = buf.remaining().min(self.read_buf.len() - self.read_pos);
+                let pos = self.read_pos;
+                buf.put_slice(&self.read_buf[pos..pos + n]);
+                self.read_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) if data.is_empty() => self.read_eof = true,
+                Some(Ok(Message::Binary(data))) => {
+                    self.read_buf = data;
+                    self.read_pos = 0;
+                },
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "text message on a binary tunnel")));
+                },
+                // Pings are answered by tungstenite.
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => {},
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Err(
+                    WsError::ConnectionClosed
+                    | WsError::AlreadyClosed
+                    | WsError::Protocol(ProtocolError::ResetWithoutClosingHandshake)
+                )) => return Poll::Ready(Ok(())),
+                Some(Err(e)) => return Poll::Ready(Err(io_error(e))),
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        // An empty message would read as end of stream.
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        ready!(self.poll_send_pending(cx))?;
+        ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(io_error)?;
+        Pin::new(&mut self.inner).start_send(Message::Binary(buf.to_vec())).map_err(io_error)?;
+        self.unflushed = true;
+        // Send it right away; if the socket is full the next read or write finishes it.
+        if let Poll::Ready(Err(e)) = self.poll_send_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        ready!(self.poll_send_pending(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx).map_err(io_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        if !self.write_eof {
+            ready!(self.poll_send_pending(cx))?;
+            ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(io_error)?;
+            Pin::new(&mut self.inner).start_send(Message::Binary(Vec::new())).map_err(io_error)?;
+            self.unflushed = true;
+            self.write_eof = true;
+        }
+        self.poll_send_pending(cx)
+    }
+}
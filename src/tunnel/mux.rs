@@ -0,0 +1,650 @@
+use crate::consts;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter, ReadBuf};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use thiserror::Error;
+
+// Many tunnel sessions over one connection. Every frame is
+//
+//     type (1) | stream id (4) | payload length (2) | payload
+//
+// OPEN carries the SOCKS5 request of a new stream, opened by the local side
+// with odd ids. DATA is stream bytes, WINDOW lets the peer send that many
+// more, FIN ends one direction, RESET (a SOCKS5 reply code) aborts the
+// stream, DATAGRAM is a SOCKS UDP datagram of a UDP association. PING, on
+// stream 0, asks the peer to answer with PONG.
+const OPEN: u8 = 0x01;
+const DATA: u8 = 0x02;
+const WINDOW: u8 = 0x03;
+const FIN: u8 = 0x04;
+const RESET: u8 = 0x05;
+const DATAGRAM: u8 = 0x06;
+const PING: u8 = 0x07;
+const PONG: u8 = 0x08;
+
+const MAX_DATA: usize = 16 * 1024;
+// Bytes a stream may have in flight before the receiver grants more.
+const STREAM_WINDOW: usize = 256 * 1024;
+// Datagrams queued per stream before new ones are dropped.
+const DATAGRAM_QUEUE: usize = 64;
+// DATA and DATAGRAM bytes queued for the connection before senders wait.
+const MAX_QUEUED: usize = 1024 * 1024;
+// Streams the peer may have open at once, further OPENs are reset.
+const MAX_STREAMS: usize = 1024;
+// A connection quiet for PING_INTERVAL is pinged, one quiet for PING_TIMEOUT
+// is closed.
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+const PING_TIMEOUT: Duration = Duration::from_secs(45);
+
+// A stream the peer reset, with the SOCKS5 reply code it gave.
+#[derive(Debug, Error)]
+#[error("stream reset with reply {0:#04x}")]
+pub struct Reset(pub u8);
+
+fn reset_error(code: u8) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::ConnectionReset, Reset(code))
+}
+
+// The reply code of a reset stream, from the error its reads and writes fail with.
+pub fn reset_code(e: &std::io::Error) -> Option<u8> {
+    e.get_ref()?.downcast_ref::<Reset>().map(|reset| reset.0)
+}
+
+struct Frame {
+    kind: u8,
+    id: u32,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    async fn read_from<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<Frame> {
+        let mut header = [0; 7];
+        reader.read_exact(&mut header).await?;
+        let id = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+        let mut payload = vec![0; u16::from_be_bytes([header[5], header[6]]) as usize];
+        reader.read_exact(&mut payload).await?;
+        Ok(Frame { kind: header[0], id, payload })
+    }
+
+    async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_u8(self.kind).await?;
+        writer.write_u32(self.id).await?;
+        writer.write_u16(self.payload.len() as u16).await?;
+        writer.write_all(&self.payload).await
+    }
+}
+
+struct State {
+    recv: VecDeque<u8>,
+    recv_eof: bool,
+    reset: Option<u8>,
+    read_waker: Option<Waker>,
+    send_window: usize,
+    write_waker: Option<Waker>,
+    // Bytes read but not yet granted back to the peer.
+    unacked: usize,
+    fin_sent: bool,
+    datagrams: Option<mpsc::Sender<Vec<u8>>>,
+}
+
+struct Stream {
+    state: Mutex<State>,
+    datagrams: tokio::sync::Mutex<mpsc::Receiver<Vec<u8>>>,
+}
+
+impl Stream {
+    fn new() -> Arc<Stream> {
+        let (tx, rx) = mpsc::channel(DATAGRAM_QUEUE);
+        Arc::new(Stream {
+            state: Mutex::new(State {
+                recv: VecDeque::new(),
+                recv_eof: false,
+                reset: None,
+                read_waker: None,
+                send_window: STREAM_WINDOW,
+                write_waker: None,
+                unacked: 0,
+                fin_sent: false,
+                datagrams: Some(tx),
+            }),
+            datagrams: tokio::sync::Mutex::new(rx),
+        })
+    }
+
+    fn reset(&self, code: u8) {
+        let mut state = self.state.lock().unwrap();
+        state.reset.get_or_insert(code);
+        state.datagrams = None;
+        if let Some(waker) = state.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = state.write_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+struct Shared {
+    streams: Mutex<HashMap<u32, Arc<Stream>>>,
+    frames: mpsc::UnboundedSender<Frame>,
+    next_id: AtomicU32,
+    closed: AtomicBool,
+    // DATA and DATAGRAM bytes not yet written, and the senders waiting for
+    // them to drop below MAX_QUEUED.
+    queued: AtomicUsize,
+    blocked: Mutex<Vec<Waker>>,
+}
+
+impl Shared {
+    fn send(&self, kind: u8, id: u32, payload: Vec<u8>) -> std::io::Result<()> {
+        if kind == DATA || kind == DATAGRAM {
+            self.queued.fetch_add(payload.len(), Ordering::Relaxed);
+        }
+        self.frames.send(Frame { kind, id, payload })
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))
+    }
+
+    // Ready once DATA or DATAGRAM frames may be queued. The check is made
+    // under `blocked` so that `written` can not miss the waker.
+    fn poll_room(&self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let mut blocked = self.blocked.lock().unwrap();
+        if self.closed.load(Ordering::Relaxed) {
+            return Poll::Ready(Err(std::io::Error::from(std::io::ErrorKind::BrokenPipe)));
+        }
+        if self.queued.load(Ordering::Relaxed) < MAX_QUEUED {
+            return Poll::Ready(Ok(()));
+        }
+        blocked.push(cx.waker().clone());
+        Poll::Pending
+    }
+
+    // The writer is done with `frame`.
+    fn written(&self, frame: &Frame) {
+        if frame.kind != DATA && frame.kind != DATAGRAM {
+            return;
+        }
+        let queued = self.queued.fetch_sub(frame.payload.len(), Ordering::Relaxed) - frame.payload.len();
+        if queued < MAX_QUEUED {
+            self.blocked.lock().unwrap().drain(..).for_each(Waker::wake);
+        }
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        for (_, stream) in self.streams.lock().unwrap().drain() {
+            stream.reset(consts::SOCKS5_REPLY_GENERAL_FAILURE);
+        }
+        self.blocked.lock().unwrap().drain(..).for_each(Waker::wake);
+    }
+
+    fn dispatch(self: &Arc<Self>, frame: Frame, incoming: Option<&mpsc::UnboundedSender<(MuxStream, Vec<u8>)>>) -> std::io::Result<()> {
+        match frame.kind {
+            PING => return self.send(PONG, 0, Vec::new()),
+            PONG => return Ok(()),
+            _ => {},
+        }
+        let stream = self.streams.lock().unwrap().get(&frame.id).cloned();
+        let stream = match (frame.kind, stream, incoming) {
+            (OPEN, None, Some(_)) if self.streams.lock().unwrap().len() >= MAX_STREAMS => {
+                return self.send(RESET, frame.id, vec![consts::SOCKS5_REPLY_GENERAL_FAILURE]);
+            },
+            (OPEN, None, Some(incoming)) => {
+                let stream = Stream::new();
+                self.streams.lock().unwrap().insert(frame.id, stream.clone());
+                let mux_stream = MuxStream { id: frame.id, shared: self.clone(), stream };
+                let _ = incoming.send((mux_stream, frame.payload));
+                return Ok(());
+            },
+            (OPEN, ..) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "unexpected OPEN frame")),
+            (DATA | WINDOW | FIN | RESET | DATAGRAM, Some(stream), _) => stream,
+            // Frames still in flight for a stream that is gone.
+            (DATA | WINDOW | FIN | RESET | DATAGRAM, None, _) => return Ok(()),
+            (kind, ..) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("unknown frame type {}", kind))),
+        };
+        let mut state = stream.state.lock().unwrap();
+        match frame.kind {
+            DATA if state.recv.len() + frame.payload.len() > STREAM_WINDOW => {
+                drop(state);
+                stream.reset(consts::SOCKS5_REPLY_GENERAL_FAILURE);
+                self.streams.lock().unwrap().remove(&frame.id);
+                self.send(RESET, frame.id, vec![consts::SOCKS5_REPLY_GENERAL_FAILURE])?;
+            },
+            DATA => {
+                state.recv.extend(&frame.payload);
+                if let Some(waker) = state.read_waker.take() {
+                    waker.wake();
+                }
+            },
+            WINDOW => {
+                let grant = frame.payload.get(..4).map(|b| u32::from_be_bytes(b.try_into().unwrap())).unwrap_or(0);
+                state.send_window += grant as usize;
+                if let Some(waker) = state.write_waker.take() {
+                    waker.wake();
+                }
+            },
+            FIN => {
+                state.recv_eof = true;
+                if let Some(waker) = state.read_waker.take() {
+                    waker.wake();
+                }
+            },
+            RESET => {
+                drop(state);
+                stream.reset(frame.payload.first().copied().unwrap_or(consts::SOCKS5_REPLY_GENERAL_FAILURE));
+                self.streams.lock().unwrap().remove(&frame.id);
+            },
+            DATAGRAM => {
+                // Like UDP, datagrams nobody picks up in time are dropped.
+                if let Some(datagrams) = &state.datagrams {
+                    let _ = datagrams.try_send(frame.payload);
+                }
+            },
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+}
+
+// Handle to a multiplexed connection, cheap to clone.
+#[derive(Clone)]
+pub struct Connection {
+    shared: Arc<Shared>,
+}
+
+impl Connection {
+    // Multiplex over `io`. Streams opened by the peer are sent to `incoming`.
+    // The returned driver moves the frames and must be polled; once it ends
+    // every stream is reset.
+    pub fn new<S>(
+        io: S,
+        incoming: Option<mpsc::UnboundedSender<(MuxStream, Vec<u8>)>>,
+    ) -> (Connection, impl Future<Output = std::io::Result<()>>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (frames, rx) = mpsc::unbounded_channel();
+        let shared = Arc::new(Shared {
+            streams: Mutex::new(HashMap::new()),
+            frames,
+            next_id: AtomicU32::new(1),
+            closed: AtomicBool::new(false),
+            queued: AtomicUsize::new(0),
+            blocked: Mutex::new(Vec::new()),
+        });
+        (Connection { shared: shared.clone() }, drive(io, shared, rx, incoming))
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Relaxed)
+    }
+
+    // Open a stream whose OPEN frame carries `request`.
+    pub fn open(&self, request: Vec<u8>) -> std::io::Result<MuxStream> {
+        if self.is_closed() {
+            return Err(std::io::Error::from(std::io::ErrorKind::NotConnected));
+        }
+        let id = self.shared.next_id.fetch_add(2, Ordering::Relaxed);
+        let stream = Stream::new();
+        self.shared.streams.lock().unwrap().insert(id, stream.clone());
+        self.shared.send(OPEN, id, request)?;
+        Ok(MuxStream { id, shared: self.shared.clone(), stream })
+    }
+}
+
+async fn drive<S>(
+    io: S,
+    shared: Arc<Shared>,
+    mut frames: mpsc::UnboundedReceiver<Frame>,
+    incoming: Option<mpsc::UnboundedSender<(MuxStream, Vec<u8>)>>,
+) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    struct CloseOnDrop(Arc<Shared>);
+    impl Drop for CloseOnDrop {
+        fn drop(&mut self) {
+            self.0.close();
+        }
+    }
+    let _close = CloseOnDrop(shared.clone());
+    let (reader, writer) = tokio::io::split(io);
+    let (mut reader, mut writer) = (BufReader::new(reader), BufWriter::new(writer));
+    let last_frame = Mutex::new(Instant::now());
+    let write = async {
+        while let Some(frame) = frames.recv().await {
+            frame.write_to(&mut writer).await?;
+            shared.written(&frame);
+            // Batch whatever else is queued into one flush.
+            while let Ok(frame) = frames.try_recv() {
+                frame.write_to(&mut writer).await?;
+                shared.written(&frame);
+            }
+            writer.flush().await?;
+        }
+        Ok(())
+    };
+    let read = async {
+        loop {
+            let frame = Frame::read_from(&mut reader).await?;
+            *last_frame.lock().unwrap() = Instant::now();
+            shared.dispatch(frame, incoming.as_ref())?;
+        }
+    };
+    // A peer that went away without closing the connection stops answering
+    // PINGs, and the connection is closed instead of holding its streams.
+    let keepalive = async {
+        loop {
+            tokio::time::sleep(PING_INTERVAL).await;
+            let quiet = last_frame.lock().unwrap().elapsed();
+            if quiet >= PING_TIMEOUT {
+                return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "tunnel peer stopped answering"));
+            }
+            if quiet >= PING_INTERVAL {
+                shared.send(PING, 0, Vec::new())?;
+            }
+        }
+    };
+    let res: std::io::Result<()> = tokio::select! {
+        res = write => res,
+        res = read => res,
+        res = keepalive => res,
+    };
+    match res {
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(()),
+        res => res,
+    }
+}
+
+// One stream of a multiplexed connection.
+pub struct MuxStream {
+    id: u32,
+    shared: Arc<Shared>,
+    stream: Arc<Stream>,
+}
+
+impl MuxStream {
+    // Handle for the datagrams of a UDP association on this stream.
+    pub fn datagrams(&self) -> Datagrams {
+        Datagrams { id: self.id, shared: self.shared.clone(), stream: self.stream.clone() }
+    }
+
+    // Abort the stream, telling the peer why.
+    pub fn reset(&self, code: u8) {
+        self.stream.reset(code);
+        let _ = self.shared.send(RESET, self.id, vec![code]);
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        self.shared.streams.lock().unwrap().remove(&self.id);
+        let state = self.stream.state.lock().unwrap();
+        if state.reset.is_none() && !(state.fin_sent && state.recv_eof) {
+            let _ = self.shared.send(RESET, self.id, vec![consts::SOCKS5_REPLY_GENERAL_FAILURE]);
+        }
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let mut state = self.stream.state.lock().unwrap();
+        // Data that arrived before a reset is still delivered.
+        if !state.recv.is_empty() {
+            let n = buf.remaining().min(state.recv.len());
+            let (front, back) = state.recv.as_slices();
+            let from_front = n.min(front.len());
+            buf.put_slice(&front[..from_front]);
+            buf.put_slice(&back[..n - from_front]);
+            state.recv.drain(..n);
+            state.unacked += n;
+            if state.unacked >= STREAM_WINDOW / 4 {
+                let grant = std::mem::take(&mut state.unacked) as u32;
+                let _ = self.shared.send(WINDOW, self.id, grant.to_be_bytes().to_vec());
+            }
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(code) = state.reset {
+            return Poll::Ready(Err(reset_error(code)));
+        }
+        if state.recv_eof {
+            return Poll::Ready(Ok(()));
+        }
+        state.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let state = self.stream.state.lock().unwrap();
+        if let Some(code) = state.reset {
+            return Poll::Ready(Err(reset_error(code)));
+        }
+        if state.fin_sent {
+            return Poll::Ready(Err(std::io::Error::from(std::io::ErrorKind::BrokenPipe)));
+        }
+        drop(state);
+        std::task::ready!(self.shared.poll_room(cx))?;
+        let mut state = self.stream.state.lock().unwrap();
+        if state.send_window == 0 {
+            state.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = buf.len().min(state.send_window).min(MAX_DATA);
+        state.send_window -= n;
+        drop(state);
+        Poll::Ready(self.shared.send(DATA, self.id, buf[..n].to_vec()).map(|_| n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let mut state = self.stream.state.lock().unwrap();
+        if !state.fin_sent && state.reset.is_none() {
+            state.fin_sent = true;
+            drop(state);
+            return Poll::Ready(self.shared.send(FIN, self.id, Vec::new()));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+// The datagram side of a stream, for UDP associations.
+#[derive(Clone)]
+pub struct Datagrams {
+    id: u32,
+    shared: Arc<Shared>,
+    stream: Arc<Stream>,
+}
+
+impl Datagrams {
+    pub async fn send(&self, datagram: &[u8]) -> std::io::Result<()> {
+        if datagram.len() > u16::MAX as usize {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "datagram too large"));
+        }
+        std::future::poll_fn(|cx| self.shared.poll_room(cx)).await?;
+        if let Some(code) = self.stream.state.lock().unwrap().reset {
+            return Err(reset_error(code));
+        }
+        self.shared.send(DATAGRAM, self.id, datagram.to_vec())
+    }
+
+    pub async fn recv(&self) -> std::io::Result<Vec<u8>> {
+        match self.stream.datagrams.lock().await.recv().await {
+            Some(datagram) => Ok(datagram),
+            None => Err(reset_error(self.stream.state.lock().unwrap().reset.unwrap_or(consts::SOCKS5_REPLY_GENERAL_FAILURE))),
+        }
+    }
+}
+
+// Long-lived connections to one remote instance, opened as needed up to
+// `size` and shared round-robin.
+pub struct Pool {
+    size: usize,
+    connections: Mutex<Vec<Connection>>,
+    next: AtomicUsize,
+    dialing: tokio::sync::Mutex<()>,
+}
+
+impl std::fmt::Debug for Pool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pool").field("size", &self.size).finish()
+    }
+}
+
+impl Pool {
+    pub fn new(size: usize) -> Pool {
+        Pool { size, connections: Mutex::new(Vec::new()), next: AtomicUsize::new(0), dialing: tokio::sync::Mutex::new(()) }
+    }
+
+    fn pick(&self, full_only: bool) -> Option<Connection> {
+        let mut connections = self.connections.lock().unwrap();
+        connections.retain(|c| !c.is_closed());
+        if connections.is_empty() || (full_only && connections.len() < self.size) {
+            return None;
+        }
+        let i = self.next.fetch_add(1, Ordering::Relaxed) % connections.len();
+        Some(connections[i].clone())
+    }
+
+    // A connection from the pool, or a new one from `dial` while the pool
+    // is not full. The new connection's driver runs in its own task.
+    pub async fn get<F, S, E>(&self, dial: F) -> Result<Connection, E>
+    where
+        F: Future<Output = Result<S, E>>,
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        if let Some(connection) = self.pick(true) {
+            return Ok(connection);
+        }
+        // One dial at a time, the others wait and share its result.
+        let _dialing = self.dialing.lock().await;
+        if let Some(connection) = self.pick(true) {
+            return Ok(connection);
+        }
+        let stream = match dial.await {
+            Ok(stream) => stream,
+            Err(e) => return self.pick(false).ok_or(e),
+        };
+        let (connection, driver) = Connection::new(stream, None);
+        tokio::spawn(async move {
+            match driver.await {
+                Ok(()) => tracing::debug!("tunnel connection closed"),
+                Err(e) => tracing::debug!("tunnel connection failed: {}", e),
+            }
+        });
+        self.connections.lock().unwrap().push(connection.clone());
+        Ok(connection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Incoming = mpsc::UnboundedReceiver<(MuxStream, Vec<u8>)>;
+
+    // A local and a remote connection over an in-memory pipe, drivers running.
+    fn pair() -> (Connection, Connection, Incoming) {
+        let (local, remote) = tokio::io::duplex(64 * 1024);
+        let (tx, incoming) = mpsc::unbounded_channel();
+        let (client, driver) = Connection::new(local, None);
+        tokio::spawn(driver);
+        let (server, driver) = Connection::new(remote, Some(tx));
+        tokio::spawn(driver);
+        (client, server, incoming)
+    }
+
+    #[tokio::test]
+    async fn frames() {
+        let mut bytes = Vec::new();
+        Frame { kind: DATA, id: 3, payload: b"abc".to_vec() }.write_to(&mut bytes).await.unwrap();
+        assert_eq!(bytes, [DATA, 0, 0, 0, 3, 0, 3, b'a', b'b', b'c']);
+        let frame = Frame::read_from(&mut &bytes[..]).await.unwrap();
+        assert_eq!((frame.kind, frame.id, frame.payload), (DATA, 3, b"abc".to_vec()));
+        let truncated = Frame::read_from(&mut &bytes[..8]).await;
+        assert_eq!(truncated.err().unwrap().kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn streams() {
+        let (client, _server, mut incoming) = pair();
+        let mut stream = client.open(b"request".to_vec()).unwrap();
+        let (mut peer, request) = incoming.recv().await.unwrap();
+        assert_eq!(request, b"request");
+        stream.write_all(b"ping").await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        peer.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"ping");
+        peer.reset(0x05);
+        let e = stream.read(&mut [0; 4]).await.unwrap_err();
+        assert_eq!(reset_code(&e), Some(0x05));
+    }
+
+    #[tokio::test]
+    async fn peer_streams_are_capped() {
+        let (client, _server, mut incoming) = pair();
+        let mut streams: Vec<_> = (0..=MAX_STREAMS).map(|_| client.open(Vec::new()).unwrap()).collect();
+        let mut accepted = Vec::new();
+        for _ in 0..MAX_STREAMS {
+            accepted.push(incoming.recv().await.unwrap());
+        }
+        let e = streams.last_mut().unwrap().read(&mut [0; 1]).await.unwrap_err();
+        assert_eq!(reset_code(&e), Some(consts::SOCKS5_REPLY_GENERAL_FAILURE));
+        assert!(incoming.try_recv().is_err());
+        // A closed stream makes room for a new one.
+        drop(accepted.pop());
+        let _stream = client.open(b"again".to_vec()).unwrap();
+        assert_eq!(incoming.recv().await.unwrap().1, b"again");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn senders_wait_for_the_queue() {
+        let (local, mut remote) = tokio::io::duplex(64 * 1024);
+        let (client, driver) = Connection::new(local, None);
+        let datagrams = client.open(Vec::new()).unwrap().datagrams();
+        let datagram = vec![0; u16::MAX as usize];
+        for _ in 0..=MAX_QUEUED / datagram.len() {
+            datagrams.send(&datagram).await.unwrap();
+        }
+        let wait = tokio::time::timeout(Duration::from_secs(1), datagrams.send(&datagram)).await;
+        assert!(wait.is_err());
+        // Once the driver writes the queue out, senders go on.
+        tokio::spawn(driver);
+        tokio::spawn(async move { tokio::io::copy(&mut remote, &mut tokio::io::sink()).await });
+        datagrams.send(&datagram).await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_peers_are_dropped() {
+        let (client, server, _incoming) = pair();
+        tokio::time::sleep(PING_TIMEOUT * 3).await;
+        assert!(!client.is_closed() && !server.is_closed());
+
+        let (local, mut remote) = tokio::io::duplex(64 * 1024);
+        let (client, driver) = Connection::new(local, None);
+        let pool = Pool::new(1);
+        pool.connections.lock().unwrap().push(client.clone());
+        let driver = tokio::spawn(driver);
+        let mut header = [0; 7];
+        remote.read_exact(&mut header).await.unwrap();
+        assert_eq!(header[0], PING);
+        let e = driver.await.unwrap().unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::TimedOut);
+        assert!(client.is_closed());
+        assert!(pool.pick(false).is_none());
+    }
+}
@@ -0,0 +1,226 @@
+use super::connect;
+use crate::socks::client::{ClientError, Credentials};
+use crate::socks::{SocksAddress, SocksCommand};
+use crate::tls;
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use rustls::pki_types::CertificateDer;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::Join;
+use tokio::net::lookup_host;
+use tokio::sync::mpsc;
+use tracing::{debug, info};
+use anyhow::Result;
+
+// Tunnel over QUIC. Every bidirectional stream is one session, started with
+// the same header as over TLS and answered with a SOCKS5 reply. Datagrams of
+// a UDP association are QUIC datagrams prefixed with the id of the stream
+// that opened it:
+//
+//     stream id (8) | SOCKS UDP datagram
+const ALPN: &[u8] = b"socks-tunnel";
+
+// Datagrams queued per association before new ones are dropped.
+const DATAGRAM_QUEUE: usize = 64;
+// Keeps an idle connection to the remote open.
+const KEEP_ALIVE: Duration = Duration::from_secs(10);
+
+// A tunnel stream: the two halves of a QUIC stream as one.
+pub type QuicStream = Join<quinn::RecvStream, quinn::SendStream>;
+
+// Hands incoming datagrams to the association they belong to.
+#[derive(Default)]
+struct Demux {
+    associations: Mutex<HashMap<u64, mpsc::Sender<Vec<u8>>>>,
+}
+
+impl Demux {
+    // Read the datagrams of `connection` until it closes.
+    fn spawn(connection: &quinn::Connection) -> Arc<Demux> {
+        let demux = Arc::new(Demux::default());
+        let (connection, running) = (connection.clone(), demux.clone());
+        tokio::spawn(async move {
+            while let Ok(datagram) = connection.read_datagram().await {
+                let Some(id) = datagram.get(..8) else { continue };
+                let id = u64::from_be_bytes(id.try_into().unwrap());
+                // Like UDP, datagrams nobody picks up in time are dropped.
+                if let Some(association) = running.associations.lock().unwrap().get(&id) {
+                    let _ = association.try_send(datagram[8..].to_vec());
+                }
+            }
+        });
+        demux
+    }
+
+    fn register(self: &Arc<Self>, connection: &quinn::Connection, id: u64) -> Datagrams {
+        let (tx, rx) = mpsc::channel(DATAGRAM_QUEUE);
+        self.associations.lock().unwrap().insert(id, tx);
+        Datagrams {
+            id,
+            connection: connection.clone(),
+            rx: Arc::new(tokio::sync::Mutex::new(rx)),
+            _registration: Arc::new(Registration { id, demux: self.clone() }),
+        }
+    }
+}
+
+// Unregisters an association once its last handle is gone.
+struct Registration {
+    id: u64,
+    demux: Arc<Demux>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.demux.associations.lock().unwrap().remove(&self.id);
+    }
+}
+
+// The datagrams of one UDP association.
+#[derive(Clone)]
+pub struct Datagrams {
+    id: u64,
+    connection: quinn::Connection,
+    rx: Arc<tokio::sync::Mutex<mpsc::Receiver<Vec<u8>>>>,
+    _registration: Arc<Registration>,
+}
+
+impl Datagrams {
+    pub fn send(&self, datagram: &[u8]) -> std::io::Result<()> {
+        let mut framed = Vec::with_capacity(8 + datagram.len());
+        framed.extend_from_slice(&self.id.to_be_bytes());
+        framed.extend_from_slice(datagram);
+        self.connection.send_datagram(framed.into()).map_err(std::io::Error::other)
+    }
+
+    pub async fn recv(&self) -> std::io::Result<Vec<u8>> {
+        let mut rx = self.rx.lock().await;
+        tokio::select! {
+            datagram = rx.recv() => datagram.ok_or_else(|| std::io::Error::from(std::io::ErrorKind::ConnectionAborted)),
+            e = self.connection.closed() => Err(e.into()),
+        }
+    }
+}
+
+// Server side: a QUIC endpoint on `addr` with the [tls] certificates and
+// client certificate settings.
+pub fn endpoint(addr: SocketAddr, acceptor: &tls::Acceptor) -> Result<quinn::Endpoint> {
+    let crypto = QuicServerConfig::try_from(acceptor.server_config(ALPN))?;
+    Ok(quinn::Endpoint::server(quinn::ServerConfig::with_crypto(Arc::new(crypto)), addr)?)
+}
+
+// Server side: a connection from a `socks local` instance, closed when dropped.
+pub struct Connection {
+    inner: quinn::Connection,
+    demux: Arc<Demux>,
+}
+
+impl Connection {
+    // Finish the handshake and return the client behind a verified certificate, if any.
+    pub async fn accept(incoming: quinn::Incoming, acceptor: &tls::Acceptor) -> std::io::Result<(Connection, Option<tls::Peer>)> {
+        let inner = incoming.await?;
+        let certs = inner.peer_identity().and_then(|identity| identity.downcast::<Vec<CertificateDer<'static>>>().ok());
+        let peer = acceptor.peer(certs.as_deref().map(Vec::as_slice))?;
+        let demux = Demux::spawn(&inner);
+        Ok((Connection { inner, demux }, peer))
+    }
+
+    // The local address the client reached, on the port of `listener`.
+    pub fn server(&self, listener: SocketAddr) -> SocketAddr {
+        SocketAddr::new(self.inner.local_ip().unwrap_or(listener.ip()), listener.port())
+    }
+
+    // The next stream the client opens, with the datagrams a UDP association on it uses.
+    pub async fn accept_stream(&self) -> std::io::Result<(QuicStream, super::Datagrams)> {
+        let (send, recv) = self.inner.accept_bi().await?;
+        let datagrams = self.demux.register(&self.inner, send.id().into());
+        Ok((tokio::io::join(recv, send), super::Datagrams::Quic(datagrams)))
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.inner.close(0u32.into(), b"");
+    }
+}
+
+// Client side: one QUIC connection to a remote instance, opened when first
+// needed and again after it closes.
+pub struct Client {
+    host: String,
+    port: u16,
+    connection: tokio::sync::Mutex<Option<(quinn::Endpoint, quinn::Connection, Arc<Demux>)>>,
+}
+
+impl std::fmt::Debug for Client {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Client").field("host", &self.host).field("port", &self.port).finish()
+    }
+}
+
+impl Client {
+    pub fn new(host: &str, port: u16) -> Client {
+        Client { host: host.to_string(), port, connection: tokio::sync::Mutex::new(None) }
+    }
+
+    // Health probe: is there, or can there be, a connection.
+    pub async fn probe(&self) -> std::io::Result<()> {
+        self.connection().await.map(|_| ())
+    }
+
+    async fn connection(&self) -> std::io::Result<(quinn::Connection, Arc<Demux>)> {
+        let mut current = self.connection.lock().await;
+        if let Some((_, connection, demux)) = current.as_ref() {
+            if connection.close_reason().is_none() {
+                return Ok((connection.clone(), demux.clone()));
+            }
+        }
+        let addr = lookup_host((self.host.as_str(), self.port)).await?
+            .next()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "can not resolve upstream"))?;
+        let bind = match addr {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        let mut crypto = (*tls::client_config()).clone();
+        crypto.alpn_protocols = vec![ALPN.to_vec()];
+        let crypto = QuicClientConfig::try_from(crypto).map_err(std::io::Error::other)?;
+        let mut config = quinn::ClientConfig::new(Arc::new(crypto));
+        let mut transport = quinn::TransportConfig::default();
+        transport.keep_alive_interval(Some(KEEP_ALIVE));
+        config.transport_config(Arc::new(transport));
+        let mut endpoint = quinn::Endpoint::client(bind)?;
+        endpoint.set_default_client_config(config);
+        let connection = endpoint.connect(addr, &self.host)
+            .map_err(std::io::Error::other)?
+            .await?;
+        info!("QUIC connection to {}", addr);
+        let demux = Demux::spawn(&connection);
+        *current = Some((endpoint, connection.clone(), demux.clone()));
+        Ok((connection, demux))
+    }
+
+    // Run `cmd` for `dst:port` on a new stream.
+    async fn open(&self, credentials: Option<&Credentials>, cmd: SocksCommand, dst: &SocksAddress, port: u16) -> Result<(QuicStream, quinn::Connection, Arc<Demux>, u64), ClientError> {
+        let (connection, demux) = self.connection().await?;
+        let (send, recv) = connection.open_bi().await.map_err(std::io::Error::from)?;
+        let id = send.id().into();
+        debug!("QUIC stream {} for {} {}:{}", id, cmd.as_str(), dst, port);
+        let stream = connect(tokio::io::join(recv, send), credentials, cmd, dst, port).await?;
+        Ok((stream, connection, demux, id))
+    }
+
+    pub async fn connect(&self, credentials: Option<&Credentials>, dst: &SocksAddress, port: u16) -> Result<QuicStream, ClientError> {
+        let (stream, ..) = self.open(credentials, SocksCommand::TCPConnect, dst, port).await?;
+        Ok(stream)
+    }
+
+    // UDP ASSOCIATE: the stream has to stay open as long as the association.
+    pub async fn udp_associate(&self, credentials: Option<&Credentials>) -> Result<(QuicStream, super::Datagrams), ClientError> {
+        let unspecified = SocksAddress::IP(Ipv4Addr::UNSPECIFIED.into());
+        let (stream, connection, demux, id) = self.open(credentials, SocksCommand::UDPAssociate, &unspecified, 0).await?;
+        Ok((stream, super::Datagrams::Quic(demux.register(&connection, id))))
+    }
+}
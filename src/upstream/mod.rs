@@ -0,0 +1,379 @@
+pub mod pool;
+
+use crate::consts;
+use crate::http::client::{self as http_client, HttpError};
+use crate::metrics::METRICS;
+use crate::outbound::Source;
+use crate::socks::client::{ClientError, Credentials, SocksClient};
+use crate::socks::SocksAddress;
+use crate::socks::traits::BoxStream;
+use crate::tls;
+use crate::transport::websocket;
+use crate::tunnel::{self, mux, quic};
+use crate::socks::SocksCommand;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use tokio::net::lookup_host;
+use tracing::{debug, info, info_span, Instrument};
+use anyhow::{anyhow, Context, Error};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum UpstreamError {
+    #[error(transparent)]
+    Socks(#[from] ClientError),
+    #[error(transparent)]
+    Http(#[from] HttpError),
+    #[error("udp associate needs a socks5 upstream as the last hop, or a multiplexed tunnel as the only one")]
+    UdpUnsupported,
+}
+
+impl UpstreamError {
+    pub fn reply_code(&self) -> u8 {
+        match self {
+            UpstreamError::Socks(e) => e.reply_code(),
+            UpstreamError::Http(e) => e.reply_code(),
+            UpstreamError::UdpUnsupported => consts::SOCKS5_REPLY_COMMAND_NOT_SUPPORTED,
+        }
+    }
+
+    // The upstream itself worked and reported that the target is unreachable,
+    // so trying another upstream will not help.
+    pub fn is_target_error(&self) -> bool {
+        match self {
+            UpstreamError::Socks(ClientError::Rejected(rep)) => *rep != consts::SOCKS5_REPLY_GENERAL_FAILURE,
+            UpstreamError::Http(HttpError::Status(status, _)) => matches!(status, 403 | 404 | 410 | 502 | 504),
+            UpstreamError::UdpUnsupported => true,
+            _ => false,
+        }
+    }
+}
+
+impl From<std::io::Error> for UpstreamError {
+    fn from(e: std::io::Error) -> Self {
+        UpstreamError::Socks(e.into())
+    }
+}
+
+// Where a UDP association through upstreams sends its datagrams.
+pub enum UdpRelay {
+    // The relay address the last SOCKS5 hop reported.
+    Socket(SocketAddr),
+    // Carried by the tunnel the association was opened on.
+    Tunnel(tunnel::Datagrams),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Scheme {
+    Socks5,
+    Http,
+    // SOCKS5 over WebSocket binary messages, plain or over TLS.
+    Ws,
+    Wss,
+    // A `socks serve` instance's tunnel listener, over TLS or QUIC.
+    Tunnel,
+    Quic,
+}
+
+impl Scheme {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Scheme::Socks5 => "socks5",
+            Scheme::Http => "http",
+            Scheme::Ws => "ws",
+            Scheme::Wss => "wss",
+            Scheme::Tunnel => "tunnel",
+            Scheme::Quic => "quic",
+        }
+    }
+
+    fn is_socks5(&self) -> bool {
+        matches!(self, Scheme::Socks5 | Scheme::Ws | Scheme::Wss)
+    }
+}
+
+/// One parent proxy in a chain, written as `socks5://[user:password@]host:port`,
+/// `http://[user:password@]host:port` for an HTTP proxy that supports CONNECT,
+/// `ws://[user:password@]host:port/path` (`wss://` for TLS) for a SOCKS5
+/// proxy behind a WebSocket listener, or `tunnel://[user:password@]host:port`
+/// for another instance's tunnel listener. Tunnels take `?connections=N`, the
+/// number of multiplexed connections to share, 0 for one connection per session.
+/// `quic://[user:password@]host:port` reaches a tunnel over QUIC, one
+/// connection with a stream per session; it has to be the first hop.
+/// Parsed with `str::parse`.
+#[derive(Debug, Clone)]
+pub struct Hop {
+    scheme: Scheme,
+    host: String,
+    port: u16,
+    // WebSocket request path, empty for the other schemes.
+    path: String,
+    credentials: Option<Credentials>,
+    // Multiplexed connections of a tunnel hop, shared by every clone.
+    mux: Option<Arc<mux::Pool>>,
+    quic: Option<Arc<quic::Client>>,
+}
+
+impl Hop {
+    pub fn is_tunnel(&self) -> bool {
+        matches!(self.scheme, Scheme::Tunnel | Scheme::Quic)
+    }
+
+    // Sessions share connections, which lets the tunnel carry UDP too.
+    pub fn is_multiplexed(&self) -> bool {
+        self.mux.is_some() || self.quic.is_some()
+    }
+
+    fn address(&self) -> SocksAddress {
+        match self.host.parse() {
+            Ok(ip) => SocksAddress::IP(ip),
+            Err(_) => SocksAddress::Domain(self.host.clone()),
+        }
+    }
+
+    // The stream the proxy protocol is spoken over, from one connected to this hop.
+    async fn transport(&self, stream: BoxStream) -> Result<BoxStream, UpstreamError> {
+        let stream: BoxStream = match self.scheme {
+            Scheme::Socks5 | Scheme::Http => stream,
+            Scheme::Tunnel => Box::new(tls::connect(stream, &self.host).await?),
+            Scheme::Quic => return Err(quic_not_first()),
+            Scheme::Ws => Box::new(websocket::connect(stream, &self.host, self.port, &self.path, false).await?),
+            Scheme::Wss => {
+                let stream = tls::connect(stream, &self.host).await?;
+                Box::new(websocket::connect(stream, &self.host, self.port, &self.path, true).await?)
+            },
+        };
+        Ok(stream)
+    }
+
+    // A multiplexed connection to this hop, the first of `chain`.
+    async fn mux_connection(&self, pool: &mux::Pool, chain: &Chain, source: &Source) -> Result<mux::Connection, UpstreamError> {
+        pool.get(async {
+            let stream = self.transport(chain.dial(source).await?).await?;
+            let stream = tunnel::hello(stream, self.credentials.as_ref()).await?;
+            info!("multiplexed connection to {}", self);
+            Ok::<_, UpstreamError>(stream)
+        }).await
+    }
+
+    // Ask this hop to open a tunnel to `dst:port` over a stream already connected to it.
+    async fn tunnel(&self, stream: BoxStream, dst: &SocksAddress, port: u16) -> Result<BoxStream, UpstreamError> {
+        let stream = self.transport(stream).await?;
+        let stream = match self.scheme {
+            Scheme::Socks5 | Scheme::Ws | Scheme::Wss => SocksClient::new(stream)
+                .connect(self.credentials.as_ref(), dst, port)
+                .await?,
+            Scheme::Http => http_client::connect(stream, self.credentials.as_ref(), &dst.to_string(), port)
+                .await?,
+            Scheme::Tunnel => tunnel::connect(stream, self.credentials.as_ref(), SocksCommand::TCPConnect, dst, port)
+                .await?,
+            Scheme::Quic => return Err(quic_not_first()),
+        };
+        Ok(stream)
+    }
+}
+
+fn quic_not_first() -> UpstreamError {
+    std::io::Error::new(std::io::ErrorKind::Unsupported, "a quic:// upstream can only be the first hop").into()
+}
+
+// Multiplexed connections per tunnel hop unless the URL says otherwise.
+const DEFAULT_CONNECTIONS: usize = 2;
+
+impl FromStr for Hop {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, rest) = match s.split_once("://") {
+            Some(("socks5", rest)) => (Scheme::Socks5, rest),
+            Some(("http", rest)) => (Scheme::Http, rest),
+            Some(("ws", rest)) => (Scheme::Ws, rest),
+            Some(("wss", rest)) => (Scheme::Wss, rest),
+            Some(("tunnel", rest)) => (Scheme::Tunnel, rest),
+            Some(("quic", rest)) => (Scheme::Quic, rest),
+            _ => return Err(anyhow!("unsupported upstream scheme in {:?}", s)),
+        };
+        let (rest, connections) = match rest.split_once('?') {
+            Some((rest, query)) if scheme == Scheme::Tunnel => match query.strip_prefix("connections=") {
+                Some(n) => (rest, n.parse().map_err(|_| anyhow!("bad connection count in {:?}", s))?),
+                None => return Err(anyhow!("unknown option {:?} in {:?}", query, s)),
+            },
+            _ => (rest, DEFAULT_CONNECTIONS),
+        };
+        let (rest, path) = match rest.find('/') {
+            Some(i) if matches!(scheme, Scheme::Ws | Scheme::Wss) => (&rest[..i], &rest[i..]),
+            Some(_) => return Err(anyhow!("upstream {:?} can not have a path", s)),
+            None if matches!(scheme, Scheme::Ws | Scheme::Wss) => (rest, "/"),
+            None => (rest, ""),
+        };
+        let (credentials, host_port) = match rest.rsplit_once('@') {
+            Some((userinfo, host_port)) => {
+                let (username, password) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+                let credentials = Credentials { username: username.to_string(), password: password.to_string() };
+                // HTTP hops send Basic auth, the others a one-byte length.
+                if scheme != Scheme::Http {
+                    credentials.check().with_context(|| format!("upstream {}://{}", scheme.as_str(), host_port))?;
+                }
+                (Some(credentials), host_port)
+            },
+            None => (None, rest),
+        };
+        let (host, port) = host_port.rsplit_once(':')
+            .ok_or_else(|| anyhow!("upstream {:?} is missing a port", s))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let port = port.parse()?;
+        Ok(Hop {
+            scheme,
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+            credentials,
+            mux: match scheme {
+                Scheme::Tunnel if connections > 0 => Some(Arc::new(mux::Pool::new(connections))),
+                _ => None,
+            },
+            quic: match scheme {
+                Scheme::Quic => Some(Arc::new(quic::Client::new(host, port))),
+                _ => None,
+            },
+        })
+    }
+}
+
+impl fmt::Display for Hop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}://{}{}", self.scheme.as_str(), crate::http::authority(&self.host, self.port), self.path)
+    }
+}
+
+// An ordered list of parent proxies. The first hop is dialed directly, every
+// following hop is reached through a CONNECT on the previous one. SOCKS5,
+// HTTP and WebSocket hops can be mixed freely. A tunnel hop only shares its
+// multiplexed connections when it comes first.
+#[derive(Debug, Clone)]
+pub struct Chain {
+    hops: Vec<Hop>,
+}
+
+impl Chain {
+    pub fn new(hops: Vec<Hop>) -> Option<Chain> {
+        if hops.is_empty() {
+            return None;
+        }
+        Some(Chain { hops })
+    }
+
+    // TCP (or QUIC) health probe: can the first hop be reached at all.
+    pub async fn probe(&self) -> Result<(), UpstreamError> {
+        match &self.hops[0].quic {
+            Some(client) => client.probe().await?,
+            None => drop(self.dial(&Source::default()).await?),
+        }
+        Ok(())
+    }
+
+    async fn dial(&self, source: &Source) -> Result<BoxStream, UpstreamError> {
+        let first = &self.hops[0];
+        let started = Instant::now();
+        let resolved = lookup_host((first.host.as_str(), first.port))
+            .instrument(info_span!("dns", host = %first.host))
+            .await
+            .map(|mut addrs| addrs.next());
+        METRICS.dns_latency.observe(started.elapsed());
+        if !matches!(resolved, Ok(Some(_))) {
+            METRICS.dns_failures.fetch_add(1, Ordering::Relaxed);
+        }
+        let addr = resolved?
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "can not resolve upstream"))?;
+        let stream = source.connect(addr).await?;
+        debug!("connected to upstream {}", first);
+        Ok(Box::new(stream))
+    }
+
+    // A stream through `hops`, the start of the chain, to `dst:port`.
+    async fn walk(&self, hops: &[Hop], dst: &SocksAddress, port: u16, source: &Source) -> Result<BoxStream, UpstreamError> {
+        let targets = hops.iter().skip(1).map(|hop| (hop.address(), hop.port))
+            .chain(std::iter::once((dst.clone(), port)));
+        let mut stream = None;
+        for (hop, (next, next_port)) in hops.iter().zip(targets) {
+            // Boxed, the handshakes of every hop kind inline overflow the stack in debug builds.
+            let opened: BoxStream = match (stream, &hop.mux, &hop.quic) {
+                (None, Some(pool), _) => {
+                    let connection = Box::pin(hop.mux_connection(pool, self, source)).await?;
+                    Box::new(tunnel::open(&connection, SocksCommand::TCPConnect, &next, next_port).await?)
+                },
+                (None, _, Some(client)) => Box::new(Box::pin(client.connect(hop.credentials.as_ref(), &next, next_port)).await?),
+                (None, None, None) => Box::pin(hop.tunnel(self.dial(source).await?, &next, next_port)).await?,
+                (Some(stream), ..) => Box::pin(hop.tunnel(stream, &next, next_port)).await?,
+            };
+            stream = Some(opened);
+            debug!("{} -> {}:{}", hop, next, next_port);
+        }
+        Ok(stream.expect("chain has hops"))
+    }
+
+    // Walk the chain up to, but not including, the handshake with the last hop.
+    async fn open(&self, source: &Source) -> Result<BoxStream, UpstreamError> {
+        match self.hops.split_last() {
+            Some((last, [])) if !last.is_multiplexed() => self.dial(source).await,
+            Some((last, hops)) => self.walk(hops, &last.address(), last.port, source).await,
+            None => unreachable!(),
+        }
+    }
+
+    pub async fn connect(&self, dst: &SocksAddress, port: u16, source: &Source) -> Result<BoxStream, UpstreamError> {
+        let stream = self.walk(&self.hops, dst, port, source).await?;
+        info!("connected to {}:{} through {}", dst, port, self);
+        Ok(stream)
+    }
+
+    // UDP ASSOCIATE on the last hop. The control connection goes through the
+    // chain, datagrams are sent straight to the relay address the last hop
+    // reports. A multiplexed tunnel carries both on one stream instead.
+    pub async fn udp_associate(&self, source: &Source) -> Result<(BoxStream, UdpRelay), UpstreamError> {
+        if let [hop @ Hop { mux: Some(pool), .. }] = self.hops.as_slice() {
+            let connection = hop.mux_connection(pool, self, source).await?;
+            let unspecified = SocksAddress::IP(std::net::Ipv4Addr::UNSPECIFIED.into());
+            let stream = tunnel::open(&connection, SocksCommand::UDPAssociate, &unspecified, 0).await?;
+            let datagrams = stream.datagrams();
+            info!("udp association through {}", self);
+            return Ok((Box::new(stream), UdpRelay::Tunnel(tunnel::Datagrams::Mux(datagrams))));
+        }
+        if let [hop @ Hop { quic: Some(client), .. }] = self.hops.as_slice() {
+            let (stream, datagrams) = client.udp_associate(hop.credentials.as_ref()).await?;
+            info!("udp association through {}", self);
+            return Ok((Box::new(stream), UdpRelay::Tunnel(datagrams)));
+        }
+        let last = self.hops.last().unwrap();
+        if !last.scheme.is_socks5() {
+            return Err(UpstreamError::UdpUnsupported);
+        }
+        let stream = self.open(source).await?;
+        let stream = last.transport(stream).await?;
+        let (control, bnd_addr, bnd_port) = SocksClient::new(stream)
+            .udp_associate(last.credentials.as_ref())
+            .await?;
+        let relay_host = match bnd_addr {
+            SocksAddress::IP(ip) if !ip.is_unspecified() => ip.to_string(),
+            SocksAddress::Domain(domain) => domain,
+            // 0.0.0.0 means "the address you reached me on"
+            _ => last.host.clone(),
+        };
+        let relay = lookup_host((relay_host.as_str(), bnd_port)).await?
+            .next()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "can not resolve udp relay"))?;
+        info!("udp relay {} through {}", relay, self);
+        Ok((control, UdpRelay::Socket(relay)))
+    }
+}
+
+impl fmt::Display for Chain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hops: Vec<String> = self.hops.iter().map(|h| h.to_string()).collect();
+        write!(f, "{}", hops.join(" -> "))
+    }
+}
@@ -0,0 +1,367 @@
+use super::{Chain, UdpRelay, UpstreamError};
+use crate::outbound::Source;
+use crate::socks::SocksAddress;
+use crate::socks::traits::BoxStream;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{interval, timeout, Duration};
+use tracing::{debug, info, warn};
+use anyhow::{anyhow, Error};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+    RoundRobin,
+    LeastConnections,
+    Random,
+    // Random, weighted by the inverse of the measured connect latency.
+    Latency,
+}
+
+impl FromStr for Strategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "least-connections" => Ok(Strategy::LeastConnections),
+            "random" => Ok(Strategy::Random),
+            "latency" => Ok(Strategy::Latency),
+            _ => Err(anyhow!("unknown strategy {:?}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Probe {
+    // Open a TCP connection to the first hop.
+    Tcp,
+    // CONNECT through the whole chain to this target.
+    Connect(SocksAddress, u16),
+}
+
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    pub probe: Probe,
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+#[derive(Debug)]
+struct Member {
+    name: String,
+    chain: Arc<Chain>,
+    up: AtomicBool,
+    active: AtomicUsize,
+    // Smoothed connect latency in microseconds, 0 until the first measurement.
+    latency: AtomicU64,
+}
+
+impl Member {
+    fn record_latency(&self, elapsed: Duration) {
+        let sample = elapsed.as_micros().max(1) as u64;
+        let old = self.latency.load(Ordering::Relaxed);
+        let new = if old == 0 { sample } else { (old * 7 + sample) / 8 };
+        self.latency.store(new, Ordering::Relaxed);
+    }
+
+    fn set_up(&self, up: bool) {
+        if self.up.swap(up, Ordering::Relaxed) != up {
+            match up {
+                true => info!("upstream {} is up", self.name),
+                false => warn!("upstream {} is down", self.name),
+            }
+        }
+    }
+}
+
+// A named group of upstream chains with a selection strategy. Members that
+// fail a health check or a handshake are skipped until they recover.
+#[derive(Debug)]
+pub struct Pool {
+    name: String,
+    members: Vec<Member>,
+    strategy: Strategy,
+    health_check: Option<HealthCheck>,
+    // Budget for one member's attempt, so a member that never answers does not
+    // use up the whole connect timeout before the next one is tried.
+    attempt_timeout: Option<Duration>,
+    next: AtomicUsize,
+}
+
+impl Pool {
+    // Each attempt gets the health-check timeout, or else an even share of
+    // `connect_timeout` across the members.
+    pub fn new(name: &str, members: Vec<(String, Arc<Chain>)>, strategy: Strategy, health_check: Option<HealthCheck>, connect_timeout: Option<Duration>) -> Pool {
+        let attempt_timeout = match &health_check {
+            Some(health_check) => Some(health_check.timeout),
+            None => connect_timeout.map(|limit| limit / members.len().max(1) as u32),
+        };
+        let members = members.into_iter()
+            .map(|(name, chain)| Member {
+                name,
+                chain,
+                up: AtomicBool::new(true),
+                active: AtomicUsize::new(0),
+                latency: AtomicU64::new(0),
+            })
+            .collect();
+        Pool {
+            name: name.to_string(),
+            members,
+            strategy,
+            health_check,
+            attempt_timeout,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Member indexes in the order they should be tried: healthy members first,
+    // ordered by the strategy, then the ones marked down as a last resort.
+    fn candidates(&self) -> Vec<usize> {
+        let (mut up, down): (Vec<usize>, Vec<usize>) = (0..self.members.len())
+            .partition(|i| self.members[*i].up.load(Ordering::Relaxed));
+        match self.strategy {
+            Strategy::RoundRobin => {
+                if !up.is_empty() {
+                    let start = self.next.fetch_add(1, Ordering::Relaxed) % up.len();
+                    up.rotate_left(start);
+                }
+            },
+            Strategy::LeastConnections => {
+                up.sort_by_key(|i| self.members[*i].active.load(Ordering::Relaxed));
+            },
+            Strategy::Random => {
+                let mut keyed: Vec<(u64, usize)> = up.iter().map(|i| (rand::random::<u64>(), *i)).collect();
+                keyed.sort();
+                up = keyed.into_iter().map(|(_, i)| i).collect();
+            },
+            Strategy::Latency => {
+                // Weighted shuffle with weight 1/latency: key = ln(u) / weight, largest first.
+                // Members without a measurement yet count as the fastest.
+                let mut keyed: Vec<(f64, usize)> = up.iter()
+                    .map(|i| {
+                        let latency = self.members[*i].latency.load(Ordering::Relaxed).max(1) as f64;
+                        (rand::random::<f64>().ln() * latency, *i)
+                    })
+                    .collect();
+                keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+                up = keyed.into_iter().map(|(_, i)| i).collect();
+            },
+        }
+        up.extend(down);
+        up
+    }
+
+    // Run one member's attempt under the per-member budget. Callers box the
+    // attempt, a chain's future is large and would grow every session's.
+    async fn attempt<T>(&self, fut: impl Future<Output = Result<T, UpstreamError>>) -> Result<T, UpstreamError> {
+        let Some(limit) = self.attempt_timeout else {
+            return fut.await;
+        };
+        match timeout(limit, fut).await {
+            Ok(res) => res,
+            Err(_) => Err(std::io::Error::new(ErrorKind::TimedOut, format!("no answer within {:?}", limit)).into()),
+        }
+    }
+
+    pub async fn connect(self: &Arc<Self>, dst: &SocksAddress, port: u16, source: &Source) -> Result<BoxStream, UpstreamError> {
+        let mut last_error = None;
+        for i in self.candidates() {
+            let member = &self.members[i];
+            let start = Instant::now();
+            match self.attempt(Box::pin(member.chain.connect(dst, port, source))).await {
+                Ok(stream) => {
+                    member.record_latency(start.elapsed());
+                    member.set_up(true);
+                    return Ok(Box::new(Pooled::new(stream, self.clone(), i)));
+                },
+                Err(e) if e.is_target_error() => return Err(e),
+                Err(e) => {
+                    warn!("upstream {} in {} failed: {}, trying the next one", member.name, self.name, e);
+                    member.set_up(false);
+                    last_error = Some(e);
+                },
+            }
+        }
+        Err(last_error.unwrap_or_else(|| std::io::Error::other("upstream group has no members").into()))
+    }
+
+    pub async fn udp_associate(&self, source: &Source) -> Result<(BoxStream, UdpRelay), UpstreamError> {
+        let mut last_error = None;
+        for i in self.candidates() {
+            let member = &self.members[i];
+            match self.attempt(Box::pin(member.chain.udp_associate(source))).await {
+                Ok(relay) => return Ok(relay),
+                Err(e) if e.is_target_error() => return Err(e),
+                Err(e) => {
+                    warn!("upstream {} in {} failed: {}, trying the next one", member.name, self.name, e);
+                    member.set_up(false);
+                    last_error = Some(e);
+                },
+            }
+        }
+        Err(last_error.unwrap_or_else(|| std::io::Error::other("upstream group has no members").into()))
+    }
+
+    // Probe every member on the configured interval. The task ends once the pool is dropped.
+    pub fn spawn_health_check(self: &Arc<Self>) {
+        let Some(health_check) = self.health_check.clone() else {
+            return;
+        };
+        let pool: Weak<Pool> = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut ticker = interval(health_check.interval);
+            loop {
+                ticker.tick().await;
+                let Some(pool) = pool.upgrade() else {
+                    return;
+                };
+                for member in &pool.members {
+                    let start = Instant::now();
+                    let probe = async {
+                        match &health_check.probe {
+                            Probe::Tcp => member.chain.probe().await,
+                            Probe::Connect(dst, port) => member.chain.connect(dst, *port, &Source::default()).await.map(|_| ()),
+                        }
+                    };
+                    let healthy = match timeout(health_check.timeout, probe).await {
+                        Ok(Ok(())) => true,
+                        Ok(Err(e)) => {
+                            debug!("health check of {} failed: {}", member.name, e);
+                            false
+                        },
+                        Err(_) => {
+                            debug!("health check of {} timed out", member.name);
+                            false
+                        },
+                    };
+                    if healthy {
+                        member.record_latency(start.elapsed());
+                    }
+                    member.set_up(healthy);
+                }
+            }
+        });
+    }
+}
+
+// Outbound stream that counts towards its member's active connections while open.
+struct Pooled {
+    inner: BoxStream,
+    pool: Arc<Pool>,
+    member: usize,
+}
+
+impl Pooled {
+    fn new(inner: BoxStream, pool: Arc<Pool>, member: usize) -> Self {
+        pool.members[member].active.fetch_add(1, Ordering::Relaxed);
+        Pooled { inner, pool, member }
+    }
+}
+
+impl Drop for Pooled {
+    fn drop(&mut self) {
+        self.pool.members[self.member].active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl AsyncRead for Pooled {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Pooled {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // A SOCKS5 upstream that accepts every CONNECT and echoes the stream.
+    async fn socks5_upstream() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut head = [0; 2];
+                    socket.read_exact(&mut head).await?;
+                    socket.read_exact(&mut vec![0; head[1] as usize]).await?;
+                    socket.write_all(&[0x05, 0x00]).await?;
+                    let mut request = [0; 4];
+                    socket.read_exact(&mut request).await?;
+                    let len = match request[3] {
+                        0x01 => 4,
+                        0x04 => 16,
+                        _ => socket.read_u8().await? as usize,
+                    };
+                    socket.read_exact(&mut vec![0; len + 2]).await?;
+                    socket.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await?;
+                    let (mut reader, mut writer) = socket.split();
+                    tokio::io::copy(&mut reader, &mut writer).await
+                });
+            }
+        });
+        format!("socks5://{}", addr)
+    }
+
+    fn chain(url: &str) -> Arc<Chain> {
+        Arc::new(Chain::new(vec![url.parse().unwrap()]).unwrap())
+    }
+
+    #[tokio::test]
+    async fn silent_members_are_skipped() {
+        // Accepts connections and never answers the greeting.
+        let silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let silent_url = format!("socks5://{}", silent.local_addr().unwrap());
+        let members = vec![
+            ("silent".to_string(), chain(&silent_url)),
+            ("working".to_string(), chain(&socks5_upstream().await)),
+        ];
+        let pool = Arc::new(Pool::new("test", members, Strategy::RoundRobin, None, Some(Duration::from_millis(400))));
+        assert_eq!(pool.attempt_timeout, Some(Duration::from_millis(200)));
+
+        let start = Instant::now();
+        let dst = SocksAddress::IP([127, 0, 0, 1].into());
+        let mut stream = pool.connect(&dst, 80, &Source::default()).await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(!pool.members[0].up.load(Ordering::Relaxed));
+        assert!(pool.members[1].up.load(Ordering::Relaxed));
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // The silent member is now tried last, and the working one answers first.
+        drop(stream);
+        let start = Instant::now();
+        pool.connect(&dst, 80, &Source::default()).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(200));
+        drop(silent);
+    }
+}
@@ -0,0 +1,87 @@
+// Helpers shared by the integration tests, each test file uses some of them.
+#![allow(dead_code)]
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+// Write `contents` to a config file of its own for this test.
+pub fn config_file(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("socks-test-{}-{}.toml", std::process::id(), name));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+// A TCP server on a free port that sends back what it reads.
+pub async fn echo_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = socket.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    addr
+}
+
+// A UDP server on a free port that sends every datagram back.
+pub async fn udp_echo_server() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0; 2048];
+        while let Ok((n, from)) = socket.recv_from(&mut buf).await {
+            let _ = socket.send_to(&buf[..n], from).await;
+        }
+    });
+    addr
+}
+
+// SOCKS5 address of `host`: an IP literal or a domain name.
+pub fn address(host: &str, port: u16) -> Vec<u8> {
+    let mut bytes = match host.parse::<std::net::IpAddr>() {
+        Ok(std::net::IpAddr::V4(ip)) => [&[0x01][..], &ip.octets()].concat(),
+        Ok(std::net::IpAddr::V6(ip)) => [&[0x04][..], &ip.octets()].concat(),
+        Err(_) => [&[0x03, host.len() as u8][..], host.as_bytes()].concat(),
+    };
+    bytes.extend_from_slice(&port.to_be_bytes());
+    bytes
+}
+
+// Greet without authentication and send `cmd` for `host:port`. Returns the
+// connection and the reply: the REP field and the bound address.
+pub async fn socks5_request(proxy: SocketAddr, cmd: u8, host: &str, port: u16) -> (TcpStream, u8, SocketAddr) {
+    let mut socket = TcpStream::connect(proxy).await.unwrap();
+    socket.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    let mut method = [0; 2];
+    socket.read_exact(&mut method).await.unwrap();
+    assert_eq!(method, [0x05, 0x00]);
+    socket.write_all(&[&[0x05, cmd, 0x00][..], &address(host, port)].concat()).await.unwrap();
+    let mut reply = [0; 4];
+    socket.read_exact(&mut reply).await.unwrap();
+    let bound = match reply[3] {
+        0x01 => {
+            let mut addr = [0; 6];
+            socket.read_exact(&mut addr).await.unwrap();
+            SocketAddr::from(([addr[0], addr[1], addr[2], addr[3]], u16::from_be_bytes([addr[4], addr[5]])))
+        },
+        atyp => {
+            assert_eq!(atyp, 0x04);
+            let mut addr = [0; 18];
+            socket.read_exact(&mut addr).await.unwrap();
+            let ip: [u8; 16] = addr[..16].try_into().unwrap();
+            SocketAddr::from((ip, u16::from_be_bytes([addr[16], addr[17]])))
+        },
+    };
+    (socket, reply[1], bound)
+}
+
+// CONNECT to `host:port`, returning the connection and the REP field.
+pub async fn socks5_connect(proxy: SocketAddr, host: &str, port: u16) -> (TcpStream, u8) {
+    let (socket, rep, _) = socks5_request(proxy, 0x01, host, port).await;
+    (socket, rep)
+}
@@ -0,0 +1,68 @@
+// `socks local` to `socks serve` over a QUIC tunnel on the loopback. Its own
+// test binary: the client TLS roots are read from $SSL_CERT_FILE once.
+mod common;
+
+use socks::Server;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+#[tokio::test]
+async fn connect_and_udp_over_quic() {
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    std::env::set_var("SSL_CERT_FILE", fixtures.join("localhost.pem"));
+    let config = common::config_file("quic-remote", &format!(r#"
+[users]
+bob = "pw"
+
+[tls]
+cert = "{}"
+key = "{}"
+
+[tunnel]
+quic = ["127.0.0.1:0"]
+"#, fixtures.join("localhost.pem").display(), fixtures.join("localhost.key").display()));
+    let remote = Server::builder().listen("127.0.0.1:0").config_file(&config).build().await.unwrap();
+    let quic = remote.quic_addrs()[0];
+    let remote_handle = remote.handle();
+    let remote_running = tokio::spawn(remote.run());
+
+    let local = Server::builder()
+        .listen("127.0.0.1:0")
+        .upstream(format!("quic://bob:pw@localhost:{}", quic.port()).parse().unwrap())
+        .upstream_udp(true)
+        .build()
+        .await
+        .unwrap();
+    let proxy = local.local_addrs()[0];
+    let local_handle = local.handle();
+    let local_running = tokio::spawn(local.run());
+
+    let target = common::echo_server().await;
+    let (mut socket, rep) = common::socks5_connect(proxy, "127.0.0.1", target.port()).await;
+    assert_eq!(rep, 0x00);
+    socket.write_all(b"over quic").await.unwrap();
+    let mut buf = [0; 9];
+    timeout(Duration::from_secs(5), socket.read_exact(&mut buf)).await.unwrap().unwrap();
+    assert_eq!(&buf, b"over quic");
+    drop(socket);
+
+    let target = common::udp_echo_server().await;
+    let (control, rep, relay) = common::socks5_request(proxy, 0x03, "0.0.0.0", 0).await;
+    assert_eq!(rep, 0x00);
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let datagram = [&[0, 0, 0][..], &common::address("127.0.0.1", target.port()), b"datagram"].concat();
+    socket.send_to(&datagram, relay).await.unwrap();
+    let mut buf = [0; 1024];
+    let n = timeout(Duration::from_secs(5), socket.recv(&mut buf)).await.unwrap().unwrap();
+    assert!(buf[..n].ends_with(b"datagram"));
+    drop(control);
+
+    local_handle.shutdown();
+    timeout(Duration::from_secs(10), local_running).await.unwrap().unwrap().unwrap();
+    remote_handle.shutdown();
+    timeout(Duration::from_secs(10), remote_running).await.unwrap().unwrap().unwrap();
+    std::fs::remove_file(config).unwrap();
+}
@@ -0,0 +1,127 @@
+// Routing rules as clients of a running server see them.
+mod common;
+
+use socks::{Acl, Request, Server};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+#[tokio::test]
+async fn cidr_block_applies_to_host_names() {
+    let target = common::echo_server().await;
+    let config = common::config_file("cidr-block", r#"
+[[rules]]
+cidr = ["127.0.0.0/8", "::1/128"]
+route = "block"
+"#);
+    let server = Server::builder().listen("127.0.0.1:0").config_file(&config).build().await.unwrap();
+    let proxy = server.local_addrs()[0];
+    let handle = server.handle();
+    let running = tokio::spawn(server.run());
+
+    let (_, rep) = common::socks5_connect(proxy, "127.0.0.1", target.port()).await;
+    assert_eq!(rep, 0x02);
+    let (_, rep) = common::socks5_connect(proxy, "localhost", target.port()).await;
+    assert_eq!(rep, 0x02);
+
+    handle.shutdown();
+    running.await.unwrap().unwrap();
+    std::fs::remove_file(config).unwrap();
+}
+
+#[tokio::test]
+async fn block_rules_apply_to_each_datagram() {
+    let allowed = common::udp_echo_server().await;
+    let blocked = common::udp_echo_server().await;
+    let config = common::config_file("udp-block", &format!(r#"
+[[rules]]
+cidr = ["127.0.0.0/8", "::1/128"]
+port = [{}]
+route = "block"
+"#, blocked.port()));
+    let server = Server::builder().listen("127.0.0.1:0").config_file(&config).build().await.unwrap();
+    let proxy = server.local_addrs()[0];
+    let handle = server.handle();
+    let running = tokio::spawn(server.run());
+
+    let (control, rep, relay) = common::socks5_request(proxy, 0x03, "0.0.0.0", 0).await;
+    assert_eq!(rep, 0x00);
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    for (host, port) in [("127.0.0.1", blocked.port()), ("localhost", blocked.port()), ("127.0.0.1", allowed.port())] {
+        let datagram = [&[0, 0, 0][..], &common::address(host, port), format!("to {}", port).as_bytes()].concat();
+        socket.send_to(&datagram, relay).await.unwrap();
+    }
+    // Only the allowed target answers, the blocked datagrams were dropped.
+    let mut buf = [0; 1024];
+    let n = timeout(Duration::from_secs(5), socket.recv(&mut buf)).await.unwrap().unwrap();
+    assert!(buf[..n].ends_with(format!("to {}", allowed.port()).as_bytes()));
+    assert!(timeout(Duration::from_millis(500), socket.recv(&mut buf)).await.is_err());
+
+    drop(control);
+    handle.shutdown();
+    running.await.unwrap().unwrap();
+    std::fs::remove_file(config).unwrap();
+}
+
+// Denies one port, for every command.
+struct DenyPort(u16);
+
+impl Acl for DenyPort {
+    fn allow(&self, request: &Request) -> bool {
+        request.port != self.0
+    }
+}
+
+#[tokio::test]
+async fn acl_sees_each_datagram() {
+    let allowed = common::udp_echo_server().await;
+    let denied = common::udp_echo_server().await;
+    let server = Server::builder().listen("127.0.0.1:0").acl(DenyPort(denied.port())).build().await.unwrap();
+    let proxy = server.local_addrs()[0];
+    let handle = server.handle();
+    let running = tokio::spawn(server.run());
+
+    let (_, rep) = common::socks5_connect(proxy, "127.0.0.1", denied.port()).await;
+    assert_eq!(rep, 0x02);
+    let (control, rep, relay) = common::socks5_request(proxy, 0x03, "0.0.0.0", 0).await;
+    assert_eq!(rep, 0x00);
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    for port in [denied.port(), allowed.port()] {
+        let datagram = [&[0, 0, 0][..], &common::address("127.0.0.1", port), format!("to {}", port).as_bytes()].concat();
+        socket.send_to(&datagram, relay).await.unwrap();
+    }
+    let mut buf = [0; 1024];
+    let n = timeout(Duration::from_secs(5), socket.recv(&mut buf)).await.unwrap().unwrap();
+    assert!(buf[..n].ends_with(format!("to {}", allowed.port()).as_bytes()));
+    assert!(timeout(Duration::from_millis(500), socket.recv(&mut buf)).await.is_err());
+
+    drop(control);
+    handle.shutdown();
+    running.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn udp_routed_to_an_upstream_needs_upstream_udp() {
+    // The upstream is never dialed: without --upstream-udp the association is
+    // refused, or served here with --direct-udp.
+    let config = common::config_file("udp-upstream", r#"
+default_route = "parent"
+
+[upstreams]
+parent = ["socks5://127.0.0.1:9"]
+"#);
+    for (direct_udp, expected) in [(false, 0x02), (true, 0x00)] {
+        let server = Server::builder().listen("127.0.0.1:0").config_file(&config).direct_udp(direct_udp).build().await.unwrap();
+        let proxy = server.local_addrs()[0];
+        let handle = server.handle();
+        let running = tokio::spawn(server.run());
+
+        let (control, rep, _) = common::socks5_request(proxy, 0x03, "0.0.0.0", 0).await;
+        assert_eq!(rep, expected);
+
+        drop(control);
+        handle.shutdown();
+        running.await.unwrap().unwrap();
+    }
+    std::fs::remove_file(config).unwrap();
+}
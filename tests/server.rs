@@ -0,0 +1,60 @@
+// A server embedded with `Server::builder()`, used by a SOCKS5 client.
+mod common;
+
+use socks::Server;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+#[tokio::test]
+async fn connect_and_drain_on_shutdown() {
+    let target = common::echo_server().await;
+    let server = Server::builder().listen("127.0.0.1:0").build().await.unwrap();
+    let proxy = server.local_addrs()[0];
+    assert_ne!(proxy.port(), 0);
+    let handle = server.handle();
+    let mut running = tokio::spawn(server.run());
+
+    let (mut socket, rep) = common::socks5_connect(proxy, "127.0.0.1", target.port()).await;
+    assert_eq!(rep, 0x00);
+    socket.write_all(b"hello").await.unwrap();
+    let mut buf = [0; 5];
+    socket.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+
+    // Shut down: no new connections, the open session goes on until it ends.
+    handle.shutdown();
+    assert!(timeout(Duration::from_millis(500), &mut running).await.is_err());
+    assert!(TcpStream::connect(proxy).await.is_err());
+    socket.write_all(b"still").await.unwrap();
+    socket.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"still");
+    drop(socket);
+    timeout(Duration::from_secs(5), running).await.unwrap().unwrap().unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn admin_stops_with_the_server() {
+    let path = std::env::temp_dir().join(format!("socks-test-{}-admin.sock", std::process::id()));
+    let server = Server::builder()
+        .listen("127.0.0.1:0")
+        .admin(socks::AdminAddr::Unix(path.clone()))
+        .build()
+        .await
+        .unwrap();
+    let handle = server.handle();
+    let running = tokio::spawn(server.run());
+
+    let mut admin = tokio::net::UnixStream::connect(&path).await.unwrap();
+    admin.write_all(b"GET /stats HTTP/1.1\r\n\r\n").await.unwrap();
+    let mut response = String::new();
+    admin.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    handle.shutdown();
+    timeout(Duration::from_secs(5), running).await.unwrap().unwrap().unwrap();
+    assert!(tokio::net::UnixStream::connect(&path).await.is_err());
+    std::fs::remove_file(path).unwrap();
+}